@@ -12,7 +12,7 @@ extern crate aphid;
 use aphid::HashMap;
 
 use howl::{Listener, SoundEvent, Vec3, VEC3_ZERO};
-use howl::worker::SoundWorker;
+use howl::worker::{SoundWorker, SoundWorkerConfig};
 use howl::engine::SoundEngineUpdate::*;
 use howl::engine::SoundRender;
 
@@ -25,7 +25,10 @@ const OPENAL_PATH: &'static str = "./native/mac/openal.dylib";
 
 fn main() {
     let rand = rand::XorShiftRng::new_unseeded();
-    let worker = SoundWorker::create(OPENAL_PATH.into(), "examples/engine/resources".into(), "ogg".into(), rand, 1_000_000, 5.0);
+    println!("output devices -> {:?}", SoundWorker::output_devices(OPENAL_PATH.into()));
+
+    let config = SoundWorkerConfig::new("examples/engine/resources".into(), vec!["ogg".into(), "opus".into(), "flac".into(), "wav".into()], rand);
+    let worker = SoundWorker::create(OPENAL_PATH.into(), config);
 
     let listener = Listener::default();
 
@@ -36,6 +39,9 @@ fn main() {
         pitch: 1.5,
         attenuation:1.0,
         loop_sound: false,
+        reverb_send: 0.0,
+        lowpass: None,
+        bus: None,
     };
     let sound_event_b = SoundEvent {
         name: "water".into(),
@@ -44,20 +50,25 @@ fn main() {
         pitch: 1.0,
         attenuation:1.0,
         loop_sound: false,
+        reverb_send: 0.0,
+        lowpass: None,
+        bus: None,
     };
 
     worker.send(Preload(vec![("teleport".into(), 1.0), ("water".into(), 1.0)])).unwrap();
 
-    worker.send(Render(SoundRender { master_gain: 1.0, sounds:vec![sound_event.clone(), sound_event_b.clone()], persistent_sounds: hashmap!["music".into() => find_me_sound(1.0)], listener: listener })).unwrap();
+    worker.send(Render(SoundRender { master_gain: 1.0, sounds:vec![sound_event.clone(), sound_event_b.clone()], persistent_sounds: hashmap!["music".into() => find_me_sound(1.0)], listener: listener, reverb: None, buses: HashMap::default() })).unwrap();
 
     std::thread::sleep(std::time::Duration::new(3, 0));
 
-    worker.send(Render(SoundRender { master_gain: 1.0, sounds:vec![sound_event_b.clone()], persistent_sounds: hashmap!["music".into() => find_me_sound(0.3)], listener: listener })).unwrap();
+    println!("worker reports -> {:?}", worker.reports());
+
+    worker.send(Render(SoundRender { master_gain: 1.0, sounds:vec![sound_event_b.clone()], persistent_sounds: hashmap!["music".into() => find_me_sound(0.3)], listener: listener, reverb: None, buses: HashMap::default() })).unwrap();
 
     for _ in 0..10 {
         std::thread::sleep(std::time::Duration::new(2, 0));
 
-        worker.send(Render(SoundRender { master_gain: 1.0, sounds:vec![sound_event.clone()], persistent_sounds: hashmap!["music".into() => find_me_sound(0.3)], listener: listener })).unwrap();
+        worker.send(Render(SoundRender { master_gain: 1.0, sounds:vec![sound_event.clone()], persistent_sounds: hashmap!["music".into() => find_me_sound(0.3)], listener: listener, reverb: None, buses: HashMap::default() })).unwrap();
     }
 
     std::thread::sleep(std::time::Duration::new(3, 0));    
@@ -73,5 +84,8 @@ fn find_me_sound(gain:f32) -> SoundEvent {
         pitch: 1.0,
         attenuation:1.0,
         loop_sound: false,
+        reverb_send: 0.0,
+        lowpass: None,
+        bus: None,
     }
 }
\ No newline at end of file
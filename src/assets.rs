@@ -0,0 +1,69 @@
+use std::fs;
+use std::fs::File;
+use std::path::{PathBuf, Path};
+
+use super::load::SoundReader;
+use super::errors::*;
+use {LoadResult, PreloadResult};
+use read_directory_paths;
+
+// where sound files come from, the paths it hands out only have to mean something to its own open/size
+// (a pack file can use the names inside it, an in memory fixture whatever keys it likes)
+pub trait AssetSource : Send + Sync {
+    // the single asset a sound name refers to, extensions are lower case in order of preference
+    fn resolve(&self, sound_name: &str, extensions: &[String]) -> Option<PathBuf>;
+
+    // the assets of a sound made of several variants, empty if there's no such group
+    fn variants(&self, sound_name: &str, extensions: &[String]) -> PreloadResult<Vec<PathBuf>>;
+
+    fn open(&self, path: &Path) -> LoadResult<SoundReader>;
+
+    // in bytes, decides whether the sound is streamed
+    fn size(&self, path: &Path) -> LoadResult<u64>;
+}
+
+// "{root}/{name}.{ext}" for a single file, or "{root}/{name}/" holding the variants
+pub struct FileSystemAssets {
+    pub root: PathBuf,
+}
+
+impl FileSystemAssets {
+    pub fn new<P: Into<PathBuf>>(root: P) -> FileSystemAssets {
+        FileSystemAssets { root: root.into() }
+    }
+}
+
+impl AssetSource for FileSystemAssets {
+    fn resolve(&self, sound_name: &str, extensions: &[String]) -> Option<PathBuf> {
+        extensions.iter().map(|extension| self.root.join(format!("{}.{}", sound_name, extension))).find(|path| path.is_file())
+    }
+
+    fn variants(&self, sound_name: &str, extensions: &[String]) -> PreloadResult<Vec<PathBuf>> {
+        let directory_path = self.root.join(sound_name);
+        if !directory_path.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut sound_paths = Vec::new();
+        for path in read_directory_paths(&directory_path)? {
+            if let Some(extension) = path.extension().and_then(|p| p.to_str()).map(|s|s.to_lowercase()) {
+                if extensions.contains(&extension) {
+                    sound_paths.push(path);
+                }
+            }
+        }
+        // directory order isn't defined, round robin and weights need a stable one
+        sound_paths.sort();
+        Ok(sound_paths)
+    }
+
+    fn open(&self, path: &Path) -> LoadResult<SoundReader> {
+        let file = File::open(path).map_err(|ioe| LoadError { path: path.to_path_buf(), reason: LoadErrorReason::FileReadError(ioe) })?;
+        Ok(Box::new(file))
+    }
+
+    fn size(&self, path: &Path) -> LoadResult<u64> {
+        let meta_data = fs::metadata(path).map_err(|ioe| LoadError { path: path.to_path_buf(), reason: LoadErrorReason::FileReadError(ioe) })?;
+        Ok(meta_data.len())
+    }
+}
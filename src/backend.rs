@@ -0,0 +1,302 @@
+use std::sync::Arc;
+use std::collections::VecDeque;
+
+use {Vec3, Gain, DistanceModel, Listener, SoundProviderResult};
+use effects::{ReverbParams, SourceEffects};
+use errors::SoundProviderError;
+
+// mirrors the OpenAL source states, backends map their own notion of state onto these
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SourceState {
+    Initial,
+    Playing,
+    Paused,
+    Stopped,
+}
+
+// requested when creating a context, and what was actually granted (None is don't care/unknown)
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct ContextAttributes {
+    pub frequency: Option<u32>, // output sample rate
+    pub refresh: Option<u32>, // mixing updates per second
+    pub mono_sources: Option<u32>,
+    pub stereo_sources: Option<u32>,
+    pub hrtf: Option<bool>,
+}
+
+// mirrors ALC_HRTF_STATUS_SOFT, Unsupported for backends (or devices) without HRTF
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum HrtfStatus {
+    Disabled,
+    Enabled,
+    Denied,
+    Required,
+    HeadphonesDetected,
+    UnsupportedFormat,
+    Unsupported,
+}
+
+// everything the sound context needs from an audio provider, buffers are created empty and filled with interleaved pcm
+pub trait SoundBackend {
+    type Buffer;
+    type StaticSource : StaticSourceBackend<Buffer=Self::Buffer>;
+    type StreamingSource : StreamingSourceBackend<Buffer=Self::Buffer>;
+
+    fn new_buffer(&self) -> SoundProviderResult<Self::Buffer>;
+    // samples are -1.0..1.0, only channel counts supports_channels accepts are expected here, callers downmix the rest
+    fn set_buffer_data(&self, buffer: &mut Self::Buffer, data: Vec<f32>, channels: u8, sample_rate: u32) -> SoundProviderResult<()>;
+
+    // looping static sources play the buffer once then repeat frames start..end, false when the backend can't (they loop all of it)
+    fn set_buffer_loop_points(&self, _buffer: &mut Self::Buffer, _start: usize, _end: usize) -> SoundProviderResult<bool> {
+        Ok(false)
+    }
+
+    fn new_static_source(&self) -> SoundProviderResult<Self::StaticSource>;
+    fn new_streaming_source(&self) -> SoundProviderResult<Self::StreamingSource>;
+
+    fn set_gain(&self, gain: Gain) -> SoundProviderResult<()>;
+    fn set_listener(&self, listener: &Listener) -> SoundProviderResult<()>;
+    fn set_distance_model(&self, distance_model: DistanceModel) -> SoundProviderResult<()>;
+
+    // interleaved in WAV order (see the channels module)
+    fn supports_channels(&self, channels: u8) -> bool {
+        channels == 1 || channels == 2
+    }
+
+    // false once the output device has gone away (e.g. headset unplugged), the context needs rebuilding
+    fn connected(&self) -> SoundProviderResult<bool> {
+        Ok(true)
+    }
+
+    fn hrtf_profiles(&self) -> SoundProviderResult<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    fn hrtf_status(&self) -> SoundProviderResult<HrtfStatus> {
+        Ok(HrtfStatus::Unsupported)
+    }
+
+    // profile is one of hrtf_profiles, None lets the backend pick
+    // false (and nothing changes) when the profile isn't one of them
+    fn set_hrtf(&self, _enabled: bool, profile: Option<&str>) -> SoundProviderResult<bool> {
+        Ok(profile.is_none())
+    }
+
+    // the environment every source's reverb send feeds, None turns it off
+    fn set_reverb(&self, _reverb: Option<&ReverbParams>) -> SoundProviderResult<()> {
+        Ok(())
+    }
+
+    // effects need backend state (effect slots, filters) so they're applied through the backend rather than the source
+    fn set_static_source_effects(&self, _source: &mut Self::StaticSource, _effects: &SourceEffects) -> SoundProviderResult<()> {
+        Ok(())
+    }
+
+    fn set_streaming_source_effects(&self, _source: &mut Self::StreamingSource, _effects: &SourceEffects) -> SoundProviderResult<()> {
+        Ok(())
+    }
+}
+
+pub trait SourceBackend {
+    fn play(&mut self) -> SoundProviderResult<()>;
+    fn stop(&mut self) -> SoundProviderResult<()>;
+    fn state(&self) -> SoundProviderResult<SourceState>;
+
+    fn set_pitch(&mut self, pitch: f32) -> SoundProviderResult<()>;
+    fn set_position(&mut self, position: Vec3) -> SoundProviderResult<()>;
+    fn set_gain(&mut self, gain: Gain) -> SoundProviderResult<()>;
+
+    // playback position in seconds, for streaming sources it's relative to the first buffer still queued
+    fn offset(&self) -> SoundProviderResult<f32>;
+    fn set_offset(&mut self, offset: f32) -> SoundProviderResult<()>;
+}
+
+pub trait StaticSourceBackend : SourceBackend {
+    type Buffer;
+
+    fn set_buffer(&mut self, buffer: Arc<Self::Buffer>) -> SoundProviderResult<()>;
+    fn clear_buffer(&mut self) -> SoundProviderResult<()>;
+    fn set_looping(&mut self, looping: bool) -> SoundProviderResult<()>;
+}
+
+pub trait StreamingSourceBackend : SourceBackend {
+    type Buffer;
+
+    fn buffers_queued(&self) -> SoundProviderResult<usize>;
+    fn buffers_processed(&self) -> SoundProviderResult<usize>;
+    fn queue_buffer(&mut self, buffer: Self::Buffer) -> SoundProviderResult<()>;
+    fn unqueue_buffer(&mut self) -> SoundProviderResult<Self::Buffer>;
+}
+
+// plays nothing, sources just keep what they were last given so the engine can be driven (and looked at) without a device
+pub struct NullBackend;
+
+#[derive(Clone, Debug)]
+pub struct NullBuffer {
+    pub data: Vec<f32>,
+    pub channels: u8,
+    pub sample_rate: u32,
+    pub loop_points: Option<(usize, usize)>,
+}
+
+pub struct NullSource {
+    pub state: SourceState, // only changes on play/stop, set it to Stopped to finish a sound
+    pub gain: Gain,
+    pub pitch: f32,
+    pub position: Vec3,
+    pub offset: f32,
+    pub looping: bool,
+    pub plays: usize, // times play was called
+    pub buffer: Option<Arc<NullBuffer>>,
+    pub queued: VecDeque<NullBuffer>, // oldest first, the first processed of them are done with
+    pub processed: usize,
+}
+
+impl NullSource {
+    fn new() -> NullSource {
+        NullSource {
+            state: SourceState::Initial,
+            gain: 1.0,
+            pitch: 1.0,
+            position: [0.0, 0.0, 0.0],
+            offset: 0.0,
+            looping: false,
+            plays: 0,
+            buffer: None,
+            queued: VecDeque::new(),
+            processed: 0,
+        }
+    }
+}
+
+impl SoundBackend for NullBackend {
+    type Buffer = NullBuffer;
+    type StaticSource = NullSource;
+    type StreamingSource = NullSource;
+
+    fn new_buffer(&self) -> SoundProviderResult<NullBuffer> {
+        Ok(NullBuffer { data: Vec::new(), channels: 1, sample_rate: 44100, loop_points: None })
+    }
+
+    fn set_buffer_data(&self, buffer: &mut NullBuffer, data: Vec<f32>, channels: u8, sample_rate: u32) -> SoundProviderResult<()> {
+        buffer.data = data;
+        buffer.channels = channels;
+        buffer.sample_rate = sample_rate;
+        Ok(())
+    }
+
+    fn set_buffer_loop_points(&self, buffer: &mut NullBuffer, start: usize, end: usize) -> SoundProviderResult<bool> {
+        buffer.loop_points = Some((start, end));
+        Ok(true)
+    }
+
+    fn new_static_source(&self) -> SoundProviderResult<NullSource> {
+        Ok(NullSource::new())
+    }
+
+    fn new_streaming_source(&self) -> SoundProviderResult<NullSource> {
+        Ok(NullSource::new())
+    }
+
+    fn set_gain(&self, _gain: Gain) -> SoundProviderResult<()> {
+        Ok(())
+    }
+
+    fn set_listener(&self, _listener: &Listener) -> SoundProviderResult<()> {
+        Ok(())
+    }
+
+    fn set_distance_model(&self, _distance_model: DistanceModel) -> SoundProviderResult<()> {
+        Ok(())
+    }
+}
+
+impl SourceBackend for NullSource {
+    fn play(&mut self) -> SoundProviderResult<()> {
+        self.state = SourceState::Playing;
+        self.plays += 1;
+        Ok(())
+    }
+
+    // as with OpenAL every queued buffer counts as processed once stopped
+    fn stop(&mut self) -> SoundProviderResult<()> {
+        self.state = SourceState::Stopped;
+        self.offset = 0.0;
+        self.processed = self.queued.len();
+        Ok(())
+    }
+
+    fn state(&self) -> SoundProviderResult<SourceState> {
+        Ok(self.state)
+    }
+
+    fn set_pitch(&mut self, pitch: f32) -> SoundProviderResult<()> {
+        self.pitch = pitch;
+        Ok(())
+    }
+
+    fn set_position(&mut self, position: Vec3) -> SoundProviderResult<()> {
+        self.position = position;
+        Ok(())
+    }
+
+    fn set_gain(&mut self, gain: Gain) -> SoundProviderResult<()> {
+        self.gain = gain;
+        Ok(())
+    }
+
+    fn offset(&self) -> SoundProviderResult<f32> {
+        Ok(self.offset)
+    }
+
+    fn set_offset(&mut self, offset: f32) -> SoundProviderResult<()> {
+        self.offset = offset;
+        Ok(())
+    }
+}
+
+impl StaticSourceBackend for NullSource {
+    type Buffer = NullBuffer;
+
+    fn set_buffer(&mut self, buffer: Arc<NullBuffer>) -> SoundProviderResult<()> {
+        self.buffer = Some(buffer);
+        self.state = SourceState::Initial;
+        self.offset = 0.0;
+        Ok(())
+    }
+
+    fn clear_buffer(&mut self) -> SoundProviderResult<()> {
+        self.buffer = None;
+        Ok(())
+    }
+
+    fn set_looping(&mut self, looping: bool) -> SoundProviderResult<()> {
+        self.looping = looping;
+        Ok(())
+    }
+}
+
+impl StreamingSourceBackend for NullSource {
+    type Buffer = NullBuffer;
+
+    fn buffers_queued(&self) -> SoundProviderResult<usize> {
+        Ok(self.queued.len())
+    }
+
+    fn buffers_processed(&self) -> SoundProviderResult<usize> {
+        Ok(self.processed)
+    }
+
+    fn queue_buffer(&mut self, buffer: NullBuffer) -> SoundProviderResult<()> {
+        self.queued.push_back(buffer);
+        Ok(())
+    }
+
+    fn unqueue_buffer(&mut self) -> SoundProviderResult<NullBuffer> {
+        if self.processed == 0 {
+            return Err(SoundProviderError::InvalidOperation("unqueue_buffer with no processed buffers"));
+        }
+        self.processed -= 1;
+        self.queued.pop_front().ok_or(SoundProviderError::InvalidOperation("unqueue_buffer with no queued buffers"))
+    }
+}
@@ -0,0 +1,140 @@
+// samples are interleaved in WAV/OpenAL order (FL FR FC LFE BL BR SL SR), loaders reorder anything else
+
+// the most any loader hands out (7.1)
+pub const MAX_CHANNELS : u8 = 8;
+
+// what's done with sounds of more than 2 channels (and with stereo ones for DownmixMono)
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Multichannel {
+    Native, // as is when the backend can play the layout, downmixed to stereo when it can't
+    DownmixStereo,
+    DownmixMono, // stereo sounds too, so they're spatialized
+}
+
+impl Default for Multichannel {
+    fn default() -> Multichannel {
+        Multichannel::Native
+    }
+}
+
+const C : f32 = 0.7071; // -3dB
+
+// (left, right) weight of each channel, per channel count
+// ITU-R BS.775 style: the fronts go straight through (so they're as loud as a stereo sound's), the centre and
+// surrounds -3dB to both or their own side, the LFE is dropped and 6.1's back centre splits evenly
+// nothing is scaled down, a mix that's loud on every channel at once can go past full scale
+fn stereo_weights(channels: u8) -> &'static [(f32, f32)] {
+    match channels {
+        3 => &[(1.0, 0.0), (0.0, 1.0), (C, C)],
+        4 => &[(1.0, 0.0), (0.0, 1.0), (C, 0.0), (0.0, C)],
+        5 => &[(1.0, 0.0), (0.0, 1.0), (C, C), (C, 0.0), (0.0, C)],
+        6 => &[(1.0, 0.0), (0.0, 1.0), (C, C), (0.0, 0.0), (C, 0.0), (0.0, C)],
+        7 => &[(1.0, 0.0), (0.0, 1.0), (C, C), (0.0, 0.0), (0.5, 0.5), (C, 0.0), (0.0, C)],
+        8 => &[(1.0, 0.0), (0.0, 1.0), (C, C), (0.0, 0.0), (C, 0.0), (0.0, C), (C, 0.0), (0.0, C)],
+        _ => &[(1.0, 0.0), (0.0, 1.0)],
+    }
+}
+
+// the channel count a sound ends up with, supported says which counts the backend can play natively
+pub fn output_channels<F: Fn(u8) -> bool>(channels: u8, multichannel: Multichannel, supported: F) -> u8 {
+    match multichannel {
+        Multichannel::DownmixMono => 1,
+        Multichannel::DownmixStereo => channels.min(2),
+        Multichannel::Native => if supported(channels) { channels } else { channels.min(2) },
+    }
+}
+
+// output_channels is one of 1, 2 or channels
+pub fn convert_channels(data: Vec<f32>, channels: u8, output_channels: u8) -> Vec<f32> {
+    if channels == output_channels {
+        return data;
+    }
+
+    let stereo = if channels <= 2 {
+        data
+    } else {
+        let weights = stereo_weights(channels);
+        let mut stereo = Vec::with_capacity(data.len() / channels as usize * 2);
+        for frame in data.chunks(channels as usize) {
+            let (mut left, mut right) = (0.0, 0.0);
+            for (sample, weight) in frame.iter().zip(weights) {
+                left += sample * weight.0;
+                right += sample * weight.1;
+            }
+            stereo.push(left);
+            stereo.push(right);
+        }
+        stereo
+    };
+
+    if output_channels == 1 && channels != 1 {
+        stereo.chunks(2).map(|frame| (frame[0] + frame[1]) * 0.5).collect()
+    } else {
+        stereo
+    }
+}
+
+// vorbis puts the centre second (and the LFE last), see the vorbis spec's channel mappings
+pub fn vorbis_to_wav_order(data: &mut [f32], channels: u8) {
+    let order : &[usize] = match channels {
+        3 => &[0, 2, 1],
+        5 => &[0, 2, 1, 3, 4],
+        6 => &[0, 2, 1, 5, 3, 4],
+        7 => &[0, 2, 1, 6, 5, 3, 4],
+        8 => &[0, 2, 1, 7, 5, 6, 3, 4],
+        _ => return,
+    };
+    let mut frame_copy = vec![0.0; channels as usize];
+    for frame in data.chunks_mut(channels as usize) {
+        if frame.len() < channels as usize {
+            break;
+        }
+        frame_copy.copy_from_slice(frame);
+        for (i, &from) in order.iter().enumerate() {
+            frame[i] = frame_copy[from];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{C, stereo_weights, convert_channels, vorbis_to_wav_order};
+
+    #[test]
+    fn surround_downmix_coefficients() {
+        assert_eq!(stereo_weights(6), &[(1.0, 0.0), (0.0, 1.0), (C, C), (0.0, 0.0), (C, 0.0), (0.0, C)]);
+        assert_eq!(stereo_weights(8), &[(1.0, 0.0), (0.0, 1.0), (C, C), (0.0, 0.0), (C, 0.0), (0.0, C), (C, 0.0), (0.0, C)]);
+
+        // 5.1 fronts come out as loud as the same stereo sound would
+        let front = convert_channels(vec![0.5, 0.25, 0.0, 0.0, 0.0, 0.0], 6, 2);
+        assert_eq!(front, convert_channels(vec![0.5, 0.25], 2, 2));
+
+        // FL FR FC LFE BL BR
+        let mixed = convert_channels(vec![0.0, 0.0, 1.0, 1.0, 0.5, 0.0], 6, 2);
+        assert_eq!(mixed, vec![C + 0.5 * C, C]);
+        assert_eq!(convert_channels(vec![0.0, 0.0, 1.0, 1.0, 0.5, 0.0], 6, 1), vec![(C + 0.5 * C + C) * 0.5]);
+    }
+
+    #[test]
+    fn stereo_and_mono_convert_without_weights() {
+        assert_eq!(convert_channels(vec![0.5, 0.25], 2, 1), vec![0.375]);
+        assert_eq!(convert_channels(vec![0.5, 0.25], 1, 1), vec![0.5, 0.25]);
+    }
+
+    #[test]
+    fn vorbis_surround_is_put_in_wav_order() {
+        // vorbis 5.1 is FL FC FR BL BR LFE
+        let (fl, fc, fr, bl, br, lfe) = (1.0, 2.0, 3.0, 4.0, 5.0, 6.0);
+        let mut data = vec![fl, fc, fr, bl, br, lfe, fl, fc, fr, bl, br, lfe];
+        vorbis_to_wav_order(&mut data, 6);
+        assert_eq!(data, vec![fl, fr, fc, lfe, bl, br, fl, fr, fc, lfe, bl, br]);
+
+        // 3 channels are FL FC FR, stereo is left alone
+        let mut data = vec![fl, fc, fr];
+        vorbis_to_wav_order(&mut data, 3);
+        assert_eq!(data, vec![fl, fr, fc]);
+        let mut data = vec![fl, fr];
+        vorbis_to_wav_order(&mut data, 2);
+        assert_eq!(data, vec![fl, fr]);
+    }
+}
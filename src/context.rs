@@ -1,16 +1,24 @@
 use alto;
-use alto::{Context, Buffer, SourceTrait};
-use alto::{Mono, Stereo};
 
 use std::sync::Arc;
 use std::path::{PathBuf};
-
-use super::load::{load_combined, load_ogg, LoadedSound, Sound};
-use super::source::{Sources, SoundSource, StreamingSoundSource, SoundSourceLoan};
-
-use {Gain, DistanceModel, SoundName, SoundEvent};
+use std::collections::{VecDeque, HashSet};
+
+use super::load::{load_combined, load_sound, should_stream, LoadedSound, Sound};
+use super::assets::AssetSource;
+use super::manifest::{Manifest, SoundDefinition, Variation, load_manifest};
+use super::select::{Selection, SelectionState};
+use super::decode::{DecodePool, DecodePolicy, DecodeJob, Decoded};
+use super::loudness::{Loudness, measure};
+use super::channels::{Multichannel, output_channels, convert_channels};
+use super::resample::{resample, Resampler};
+use super::source::{Sources, SoundSource, StreamingSoundSource, StreamLoop, SoundSourceLoan};
+use super::backend::{SoundBackend, SourceBackend, StaticSourceBackend};
+use super::effects::ReverbParams;
+use super::mix::{self, Buses, BusMix, DuckRule, Ducking, MixSnapshot, Snapshots};
+
+use {Gain, DistanceModel, SoundName, SoundEvent, BusName};
 use {SoundProviderResult, PreloadResult, SoundEventResult};
-use read_directory_paths;
 use super::errors::*;
 use super::Listener;
 
@@ -19,33 +27,107 @@ use aphid::HashMap;
 use rand;
 use rand::Rng;
 
-pub struct SoundContext<'d> {
-    pub context: &'d Context<'d>,
+pub struct SoundContext<B: SoundBackend> {
+    pub backend: B,
     pub rng: rand::XorShiftRng,
-    pub path: String,
-    pub extension: String,
-    pub sources: Sources<'d>,
-    pub buffers: HashMap<SoundName, Vec<SoundBuffer<'d>>>,
+    pub assets: Arc<AssetSource>,
+    pub manifest: Manifest, // sounds.toml from the assets, empty without one
+    pub extensions: Vec<String>, // lower case, in order of preference when a name matches several files
+    pub sources: Sources<B>,
+    pub buffers: HashMap<SoundName, Vec<SoundBuffer<B>>>,
     pub stream_above_file_size: u64,
     pub stream_buffer_duration: f32,
+    pub resample_rate: Option<u32>, // every sound is converted to this rate when loaded
     pub master_gain : Gain,
     pub distance_model : DistanceModel,
     pub listener : Listener,
+    pub hrtf : Option<(bool, Option<String>)>, // last requested (enabled, profile)
+    pub reverb : Option<ReverbParams>,
+    pub buses : Buses,
+    pub ducking : Ducking,
+    pub snapshots : Snapshots,
+    pub multichannel : HashMap<SoundName, Multichannel>, // Native for anything not in here
+    pub selections : HashMap<SoundName, SelectionState>, // of sounds that have been played
+    pub decoder : Option<DecodePool>, // None decodes sounds on the spot the first time they're played
+    pub decode_policy : DecodePolicy,
+    pub decoding : HashMap<SoundName, Vec<(SoundEvent, u64)>>, // one shot events waiting on the sound, with when they were triggered
+    pub decode_generation : u64, // bumped by purge so decodes already underway are ignored
+    pub decode_failures : HashSet<SoundName>, // sounds that failed to decode (or buffer) this generation
+    pub unmeasured_streams : HashSet<SoundName>, // streams already reported as having no manifest loudness
+    pub loudness_target : Option<f32>, // LUFS static sounds are normalized to, None leaves them as they are
 }
 
-pub struct SoundBuffer<'d> {
-    pub inner : Arc<Buffer<'d, 'd>>,
-    pub gain: Gain,
+pub struct SoundBuffer<B: SoundBackend> {
+    pub inner : Arc<B::Buffer>,
+    pub gain: Gain, // what sources playing it are scaled by, the preload gain times the loudness normalization
+    pub preload_gain: Gain,
+    pub loudness: Loudness,
     pub duration: f32, // we could track last used .... could be interesting if nothing else
+    pub loop_seconds: Option<(f32, f32)>, // the region a looping play repeats after its intro, None loops all of it
+}
+
+// what survives rebuilding the context on another device, buffers are reloaded by name
+#[derive(Debug, Clone)]
+pub struct ContextRestore {
+    pub preloads: Vec<(SoundName, Gain)>,
+    pub master_gain: Gain,
+    pub listener: Listener,
+    pub distance_model: DistanceModel,
+    pub hrtf: Option<(bool, Option<String>)>,
+    pub reverb: Option<ReverbParams>,
+    pub buses: Buses,
+    pub duck_rules: Vec<DuckRule>,
+    pub snapshots: Vec<(String, MixSnapshot)>,
+    pub multichannel: HashMap<SoundName, Multichannel>,
+}
+
+// an offset past the end of a buffer is an error for OpenAL, looping sounds wrap around instead (into their loop region once past the intro)
+fn buffer_offset(offset: f32, duration: f32, loop_seconds: Option<(f32, f32)>, looping: bool) -> Option<f32> {
+    if offset <= 0.0 || duration <= 0.0 {
+        None
+    } else if looping {
+        match loop_seconds {
+            Some((start, end)) if end > start => {
+                if offset < end {
+                    Some(offset)
+                } else {
+                    Some(start + (offset - end) % (end - start))
+                }
+            },
+            _ => Some(offset % duration),
+        }
+    } else if offset < duration {
+        Some(offset)
+    } else {
+        None
+    }
 }
 
-pub fn create_sound_context<'d>(context: &'d Context<'d>, path:&str, extension: &str, rng: rand::XorShiftRng, stream_above_file_size: u64, stream_buffer_duration: f32) -> SoundContext<'d> {
+fn current_bus_mix(buses: &Buses, ducking: &Ducking, snapshots: &Snapshots, bus: Option<&BusName>) -> BusMix {
+    mix::bus_mix(buses, bus, |name| {
+        let mut modifier = snapshots.bus_mix(name);
+        modifier.gain *= ducking.gain(name);
+        modifier
+    })
+}
+
+// picks one of count variants of the sound with its manifest selection (uniform without one)
+fn select_variant<R: Rng>(manifest: &Manifest, selections: &mut HashMap<SoundName, SelectionState>, rng: &mut R, sound_name: &str, count: usize) -> Option<usize> {
+    let state = selections.entry(sound_name.to_string()).or_insert_with(SelectionState::new);
+    match manifest.sounds.get(sound_name) {
+        Some(definition) => state.select(definition.selection, &definition.weights, definition.avoid_last, count, rng),
+        None => state.select(Selection::Uniform, &[], 1, count, rng),
+    }
+}
+
+pub fn create_sound_context<B: SoundBackend>(backend: B, assets: Arc<AssetSource>, extensions: &[String], rng: rand::XorShiftRng, stream_above_file_size: u64, stream_buffer_duration: f32, resample_rate: Option<u32>) -> SoundContext<B> {
     // we should probably create our sources here
-    SoundContext {
-        context: context,
+    let mut context = SoundContext {
+        backend: backend,
         rng: rng,
-        path: String::from(path),
-        extension: String::from(extension),
+        assets: assets,
+        manifest: Manifest::default(),
+        extensions: extensions.iter().map(|e| e.to_lowercase()).collect(),
         sources: Sources {
             next_event: 0,
             sources: Vec::new(),
@@ -54,15 +136,32 @@ pub fn create_sound_context<'d>(context: &'d Context<'d>, path:&str, extension:
         buffers: HashMap::default(),
         stream_above_file_size: stream_above_file_size,
         stream_buffer_duration: stream_buffer_duration,
+        resample_rate: resample_rate,
         master_gain: 1.0,
         distance_model: alto::DistanceModel::None,
         listener: Listener::default() ,
-    }
+        hrtf: None,
+        reverb: None,
+        buses: HashMap::default(),
+        ducking: Ducking::new(),
+        snapshots: Snapshots::new(),
+        multichannel: HashMap::default(),
+        selections: HashMap::default(),
+        decoder: None,
+        decode_policy: DecodePolicy::default(),
+        decoding: HashMap::default(),
+        decode_generation: 0,
+        decode_failures: HashSet::new(),
+        unmeasured_streams: HashSet::new(),
+        loudness_target: None,
+    };
+    context.reload_manifest();
+    context
 }
 
-impl<'d> SoundContext<'d> {
+impl<B: SoundBackend> SoundContext<B> {
     pub fn set_gain(&mut self, gain: Gain) -> SoundProviderResult<()> {
-        self.context.set_gain(gain)?;
+        self.backend.set_gain(gain)?;
         self.master_gain = gain;
 
         Ok(())
@@ -70,20 +169,18 @@ impl<'d> SoundContext<'d> {
 
     pub fn create(&mut self, static_count: usize, streaming_count: usize) -> SoundProviderResult<()> {
         for _ in 0..static_count {
-            let source = self.context.new_static_source()?;
+            let source = self.backend.new_static_source()?;
             self.sources.sources.push(SoundSource { inner: source, current_binding: None});
         }
         for _ in 0..streaming_count {
-            let source = self.context.new_streaming_source()?;
-            self.sources.streaming.push(StreamingSoundSource { inner: source, stream_reader: None, current_binding: None });
+            let source = self.backend.new_streaming_source()?;
+            self.sources.streaming.push(StreamingSoundSource { inner: source, stream_reader: None, stream_loop: None, stream_position: 0, carry: Vec::new(), multichannel: Multichannel::Native, resampler: None, current_binding: None, queued_durations: VecDeque::new(), played_duration: 0.0 });
         }
         Ok(())
     }
 
     pub fn set_listener(&mut self, listener: Listener) -> SoundProviderResult<()> {
-        self.context.set_position(listener.position)?;
-        self.context.set_velocity(listener.velocity)?;
-        self.context.set_orientation::<[f32; 3]>((listener.orientation_forward.into(), listener.orientation_up.into()))?;
+        self.backend.set_listener(&listener)?;
 
         self.listener = listener;
         
@@ -93,15 +190,133 @@ impl<'d> SoundContext<'d> {
     pub fn purge(&mut self) -> SoundProviderResult<()> {
         self.sources.purge()?;
         self.buffers.clear();
+        self.decoding.clear();
+        self.decode_generation += 1;
+        self.decode_failures.clear();
+        self.unmeasured_streams.clear();
         Ok(())
     }
 
     pub fn set_distace_model(&mut self, distance_model: DistanceModel) -> SoundProviderResult<()> {
-        self.context.set_distance_model(distance_model)?;
+        self.backend.set_distance_model(distance_model)?;
         self.distance_model = distance_model;
         Ok(())
     }
 
+    pub fn restore_point(&self) -> ContextRestore {
+        ContextRestore {
+            preloads: self.buffers.iter().map(|(name, buffers)| (name.clone(), buffers.first().map(|b| b.preload_gain).unwrap_or(1.0))).collect(),
+            master_gain: self.master_gain,
+            listener: self.listener,
+            distance_model: self.distance_model,
+            hrtf: self.hrtf.clone(),
+            reverb: self.reverb,
+            buses: self.buses.clone(),
+            duck_rules: self.ducking.rules.clone(),
+            snapshots: self.snapshots.pushed(),
+            multichannel: self.multichannel.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, restore: ContextRestore) -> SoundProviderResult<()> {
+        self.multichannel = restore.multichannel;
+        self.set_gain(restore.master_gain)?;
+        self.set_listener(restore.listener)?;
+        self.set_distace_model(restore.distance_model)?;
+        if let Some((enabled, profile)) = restore.hrtf {
+            if !self.set_hrtf(enabled, profile.clone())? {
+                println!("Sound context's HRTF profile {:?} isn't available on this device", profile);
+            }
+        }
+        self.set_reverb(restore.reverb)?;
+        self.set_buses(restore.buses)?;
+        self.set_duck_rules(restore.duck_rules);
+        for (name, snapshot) in restore.snapshots {
+            self.push_snapshot(name, snapshot, 0.0);
+        }
+        for (sound_name, gain) in restore.preloads {
+            match self.preload(&sound_name, gain) {
+                Ok(_) => (),
+                Err(PreloadError::SoundProviderError(sp)) => return Err(sp),
+                Err(err) => println!("Sound Worker failed to reload {:?} err -> {:?}", sound_name, err),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_reverb(&mut self, reverb: Option<ReverbParams>) -> SoundProviderResult<()> {
+        self.backend.set_reverb(reverb.as_ref())?;
+        self.reverb = reverb;
+        Ok(())
+    }
+
+    // includes ducking and snapshots
+    pub fn bus_mix(&self, bus: Option<&BusName>) -> BusMix {
+        current_bus_mix(&self.buses, &self.ducking, &self.snapshots, bus)
+    }
+
+    fn refresh_mix(&mut self) -> SoundProviderResult<()> {
+        let buses = &self.buses;
+        let ducking = &self.ducking;
+        let snapshots = &self.snapshots;
+        self.sources.update_mix(&self.backend, |sound_event| current_bus_mix(buses, ducking, snapshots, sound_event.bus.as_ref()))
+    }
+
+    // playing sources pick up the new bus gains straight away
+    pub fn set_buses(&mut self, buses: Buses) -> SoundProviderResult<()> {
+        self.buses = buses;
+        self.refresh_mix()
+    }
+
+    // takes effect (and starts transitioning) on the next update_mix
+    pub fn push_snapshot(&mut self, name: String, snapshot: MixSnapshot, transition: f32) {
+        self.snapshots.push(name, snapshot, transition);
+    }
+
+    pub fn pop_snapshot(&mut self, name: &str, transition: f32) {
+        self.snapshots.pop(name, transition);
+    }
+
+    // ducks in progress start over from unducked
+    pub fn set_duck_rules(&mut self, rules: Vec<DuckRule>) {
+        if self.ducking.rules != rules {
+            self.ducking.set_rules(rules);
+        }
+    }
+
+    // moves ducking (based on what's bound right now, check bindings first so finished sounds don't count) and snapshot transitions along to time_ns
+    pub fn update_mix(&mut self, time_ns: u64) -> SoundProviderResult<()> {
+        let ducked = {
+            let buses = &self.buses;
+            let sources = &self.sources;
+            self.ducking.update(time_ns, |rule| sources.any_bound(|sound_event| mix::is_under(buses, sound_event.bus.as_ref(), &rule.trigger)))
+        };
+        let transitioned = self.snapshots.update(time_ns);
+        if ducked || transitioned {
+            self.refresh_mix()?;
+        }
+        Ok(())
+    }
+
+    pub fn multichannel(&self, sound_name: &str) -> Multichannel {
+        self.multichannel.get(sound_name).cloned().unwrap_or_default()
+    }
+
+    // loaded buffers of the sound are dropped so it's reloaded with the new setting on next use
+    pub fn set_multichannel(&mut self, sound_name: SoundName, multichannel: Multichannel) {
+        self.buffers.remove(&sound_name);
+        self.multichannel.insert(sound_name, multichannel);
+    }
+
+    // false when the profile isn't one the backend has, the HRTF settings are left as they were
+    pub fn set_hrtf(&mut self, enabled: bool, profile: Option<String>) -> SoundProviderResult<bool> {
+        let applied = self.backend.set_hrtf(enabled, profile.as_ref().map(|p| p.as_str()))?;
+        if applied {
+            self.hrtf = Some((enabled, profile));
+        }
+        Ok(applied)
+    }
+
     // just convenience
     pub fn stop(&mut self, loan:SoundSourceLoan) -> SoundProviderResult<()> {
         if let Some(ref mut source) = self.sources.for_loan(loan) {
@@ -110,27 +325,22 @@ impl<'d> SoundContext<'d> {
         Ok(())
     }
 
+    // a broken manifest is reported and the previous one kept
+    pub fn reload_manifest(&mut self) {
+        match load_manifest(&*self.assets) {
+            Ok(manifest) => self.manifest = manifest,
+            Err(err) => println!("Sound context failed to load the sound manifest {:?}", err),
+        }
+    }
+
     pub fn full_sound_paths(&self, sound_name:&str) -> PreloadResult<Vec<PathBuf>> {
-        // 1. look for a directory with that name
-        let ogg_path = PathBuf::from(format!("{}/{}.{}", &self.path, sound_name, &self.extension));
-        let directory_path = PathBuf::from(format!("{}/{}", &self.path, sound_name));
-
-        if ogg_path.exists() {
-            Ok(vec![ogg_path])
-        } else if directory_path.is_dir() {
-            let mut sound_paths = Vec::new();
-            let paths = read_directory_paths(&directory_path)?;
-            for path in paths {
-                if let Some(extension) = path.extension().and_then(|p| p.to_str()).map(|s|s.to_lowercase()) {
-                    if extension == self.extension {
-                        sound_paths.push(path);
-                    }
-                }
-            }
-//            println!("ok loading multi paths {:?}", sound_paths);
-            Ok(sound_paths)
+        // 1. the manifest's variants, then a single asset with that name, then a group of variants
+        if let Some(paths) = self.manifest.variant_paths(sound_name, &*self.assets, &self.extensions) {
+            Ok(paths?)
+        } else if let Some(path) = self.assets.resolve(sound_name, &self.extensions) {
+            Ok(vec![path])
         } else {
-            Ok(vec![])
+            self.assets.variants(sound_name, &self.extensions)
         }
     }
 
@@ -140,8 +350,9 @@ impl<'d> SoundContext<'d> {
         let mut buffers = Vec::new();
 
         for path in paths {
-            let sound = load_ogg(&path)?;
-            let buffer = self.buffer_sound(sound, gain)?;
+            let sound = load_sound(&*self.assets, &path)?;
+            let loudness = measure(&sound);
+            let buffer = self.buffer_sound(sound, loudness, self.multichannel(sound_name), gain)?;
             buffers.push(buffer);
         }
 
@@ -150,36 +361,85 @@ impl<'d> SoundContext<'d> {
         Ok(())
     }
 
-    pub fn buffer_sound(&self, sound: Sound, gain:Gain) -> PreloadResult<SoundBuffer<'d>> {
-        let mut buffer = try!(self.context.new_buffer());
+    // streams are too long to measure as they're loaded, the manifest says how loud they are
+    fn stream_gain(&mut self, sound_name: &str, definition: Option<&SoundDefinition>) -> Gain {
+        let target = match self.loudness_target {
+            Some(target) => target,
+            None => return 1.0,
+        };
+        match definition.and_then(|d| d.loudness) {
+            Some(integrated) => Loudness { integrated: integrated, peak: 0.0 }.normalization(target),
+            None => {
+                if self.unmeasured_streams.insert(sound_name.to_string()) {
+                    println!("stream {} has no loudness in the manifest, it's played without normalization", sound_name);
+                }
+                1.0
+            },
+        }
+    }
+
+    pub fn buffer_sound(&self, sound: Sound, loudness: Loudness, multichannel: Multichannel, gain:Gain) -> PreloadResult<SoundBuffer<B>> {
+        let sound = match self.resample_rate {
+            Some(sample_rate) => resample(sound, sample_rate),
+            None => sound,
+        };
+        let mut buffer = try!(self.backend.new_buffer());
         let duration = sound.duration();
-        if sound.channels == 1 {
-            try!(buffer.set_data::<Mono<i16>, _>(sound.data, sound.sample_rate as i32));
-        } else if sound.channels == 2 {
-            try!(buffer.set_data::<Stereo<i16>, _>(sound.data, sound.sample_rate as i32));
-        } else {
-            // bail!(ErrorKind::TooManyChannels);
+        let frames = (sound.data.len() / (sound.channels.max(1) as usize)) as u64;
+        let loop_frames = sound.loop_region.and_then(|region| region.frames(frames));
+        let channels = output_channels(sound.channels, multichannel, |c| self.backend.supports_channels(c));
+        let data = convert_channels(sound.data, sound.channels, channels);
+        try!(self.backend.set_buffer_data(&mut buffer, data, channels, sound.sample_rate));
+        let mut loop_seconds = None;
+        if let Some((start, end)) = loop_frames {
+            // without backend support it loops whole, intro included
+            if try!(self.backend.set_buffer_loop_points(&mut buffer, start as usize, end as usize)) {
+                let rate = sound.sample_rate as f32;
+                loop_seconds = Some((start as f32 / rate, end as f32 / rate));
+            }
         }
 
-        Ok(SoundBuffer{ inner: Arc::new(buffer), gain: gain, duration: duration })
+        let normalization = self.loudness_target.map(|target| loudness.normalization(target)).unwrap_or(1.0);
+        Ok(SoundBuffer{ inner: Arc::new(buffer), gain: gain * normalization, preload_gain: gain, loudness: loudness, duration: duration, loop_seconds: loop_seconds })
     }
 
     pub fn play_event(&mut self, sound_event: SoundEvent, loan: Option<SoundSourceLoan>) -> SoundEventResult<SoundSourceLoan> {
+        self.play_event_from(sound_event, loan, 0.0)
+    }
+
+    // offset (seconds) only applies when a new source is loaned, an existing loan just takes the event
+    pub fn play_event_from(&mut self, mut sound_event: SoundEvent, loan: Option<SoundSourceLoan>, offset: f32) -> SoundEventResult<SoundSourceLoan> {
+        let definition = self.manifest.sounds.get(&sound_event.name).cloned();
+        if let Some(ref definition) = definition {
+            definition.apply(&mut sound_event);
+        }
+        let looping = sound_event.loop_sound;
+        let bus_mix = self.bus_mix(sound_event.bus.as_ref());
+
         if let Some(l) = loan {
             if let Some(mut s) = self.sources.for_loan(l) {
                 // we have a loan, just apply the event
-                s.assign_event(sound_event, l.event_id)?;
+                let (variation, sound_gain) = (s.variation(), s.sound_gain());
+                s.assign_event(&self.backend, sound_event, variation, sound_gain, &bus_mix, l.event_id)?;
                 return Ok(l)
             }
         } 
+
+        let variation = match definition {
+            Some(ref definition) => definition.variation(&mut self.rng),
+            None => Variation::none(),
+        };
         
         if let Some(buffers) = self.buffers.get(&sound_event.name) {
             // sound is already loaded
             return if let Some((ref mut source, loan)) = self.sources.loan_next_free_static() {
 //                 println!("we have a sound event {:?} and now a loan {:?}", sound_event, loan);
-                if let Some(buffer) = self.rng.choose(buffers) {
+                if let Some(buffer) = select_variant(&self.manifest, &mut self.selections, &mut self.rng, &sound_event.name, buffers.len()).map(|i| &buffers[i]) {
                     source.inner.set_buffer(buffer.inner.clone())?;
-                    source.assign_event(sound_event, loan.event_id)?;
+                    source.assign_event(&self.backend, sound_event, variation, buffer.gain, &bus_mix, loan.event_id)?;
+                    if let Some(offset) = buffer_offset(offset, buffer.duration, buffer.loop_seconds, looping) {
+                        source.inner.set_offset(offset)?;
+                    }
                     source.inner.play().map_err(SoundEventError::SoundProviderError)?;
                     Ok(loan)
                 } else {
@@ -191,25 +451,48 @@ impl<'d> SoundContext<'d> {
         }
 
         let full_paths = self.full_sound_paths(&sound_event.name)?;
+        if full_paths.is_empty() {
+            // no such sound, nothing to decode
+            return Err(SoundEventError::NoSounds);
+        }
+        let stream = definition.as_ref().and_then(|d| d.stream);
+
+        // static sounds go to the pool when there is one, streams only read their headers here
+        if let Some(ref decoder) = self.decoder {
+            if !should_stream(&*self.assets, &full_paths, stream, self.stream_above_file_size)? {
+                if self.decode_failures.contains(&sound_event.name) {
+                    return Err(SoundEventError::DecodeFailed);
+                }
+                if !self.decoding.contains_key(&sound_event.name) {
+                    decoder.submit(DecodeJob { sound_name: sound_event.name.clone(), paths: full_paths, generation: self.decode_generation });
+                    self.decoding.insert(sound_event.name.clone(), Vec::new());
+                }
+                return Err(SoundEventError::Decoding);
+            }
+        }
 
         // ok we need to load/stream it
-        let combined_load = load_combined(&full_paths, self.stream_above_file_size)?;
+        let combined_load = load_combined(&*self.assets, &full_paths, stream, self.stream_above_file_size)?;
 
         // we need to call out here ...
         match combined_load {
             LoadedSound::Static(sounds) => {
                 let mut buffers = Vec::new();
                 for sound in sounds {
-                    let buffer = self.buffer_sound(sound, 1.0)?;
+                    let loudness = measure(&sound);
+                    let buffer = self.buffer_sound(sound, loudness, self.multichannel(&sound_event.name), 1.0)?;
                     buffers.push(buffer);
                 }
 
                 let sound_event_name = sound_event.name.clone();
 
                 let result = if let Some((source, loan)) = self.sources.loan_next_free_static() {
-                    if let Some(buffer) = self.rng.choose(&buffers) {
+                    if let Some(buffer) = select_variant(&self.manifest, &mut self.selections, &mut self.rng, &sound_event_name, buffers.len()).map(|i| &buffers[i]) {
                         try!(source.inner.set_buffer(buffer.inner.clone()));
-                        try!(source.assign_event(sound_event, loan.event_id));
+                        try!(source.assign_event(&self.backend, sound_event, variation, buffer.gain, &bus_mix, loan.event_id));
+                        if let Some(offset) = buffer_offset(offset, buffer.duration, buffer.loop_seconds, looping) {
+                            try!(source.inner.set_offset(offset));
+                        }
                         try!(source.inner.play());
                         Ok(loan)
                     } else {
@@ -223,12 +506,29 @@ impl<'d> SoundContext<'d> {
 
                 result
             },
-            LoadedSound::Streaming(ogg_stream_reader) => {
+            LoadedSound::Streaming(stream) => {
+                let sound_gain = self.stream_gain(&sound_event.name, definition.as_ref());
                 return if let Some((source, loan)) = self.sources.loan_next_free_streaming() {
-                    source.stream_reader = Some((ogg_stream_reader, full_paths[0].clone()));
+                    source.resampler = match self.resample_rate {
+                        Some(sample_rate) if sample_rate != stream.sample_rate() => Some(Resampler::new(stream.sample_rate(), sample_rate, stream.channels())),
+                        _ => None,
+                    };
+                    // the whole stream without a (sensible) loop region
+                    source.stream_loop = if looping {
+                        let region = stream.loop_region().and_then(|region| if region.end.map(|end| end > region.start).unwrap_or(true) { Some(region) } else { None });
+                        let (start, end) = region.map(|region| (region.start, region.end)).unwrap_or((0, None));
+                        Some(StreamLoop::new(self.assets.clone(), start, end))
+                    } else {
+                        None
+                    };
+                    source.stream_reader = Some((stream, full_paths[0].clone()));
+                    source.multichannel = self.multichannel.get(&sound_event.name).cloned().unwrap_or_default();
+                    if offset > 0.0 {
+                        try!(source.skip(offset));
+                    }
 
-                    try!(source.ensure_buffers_queued(self.context, self.stream_buffer_duration));
-                    try!(source.assign_event(sound_event, loan.event_id));
+                    try!(source.ensure_buffers_queued(&self.backend, self.stream_buffer_duration));
+                    try!(source.assign_event(&self.backend, sound_event, variation, sound_gain, &bus_mix, loan.event_id));
                     try!(source.inner.play());
 
                     Ok(loan)
@@ -239,10 +539,81 @@ impl<'d> SoundContext<'d> {
         }
     }
 
+    // for one shot events, while their sound decodes in the background the decode policy decides what becomes of them
+    pub fn trigger_event(&mut self, sound_event: SoundEvent, time_ns: u64) -> SoundEventResult<()> {
+        match self.play_event(sound_event.clone(), None) {
+            Err(SoundEventError::Decoding) => {
+                if self.decode_policy != DecodePolicy::Drop {
+                    if let Some(waiting) = self.decoding.get_mut(&sound_event.name) {
+                        waiting.push((sound_event, time_ns));
+                    }
+                }
+                Ok(())
+            },
+            result => result.map(|_| ()),
+        }
+    }
+
+    // buffers whatever the pool has finished and plays the events that were waiting on it
+    pub fn finish_decodes(&mut self, time_ns: u64) -> SoundProviderResult<()> {
+        let decoded = match self.decoder {
+            Some(ref decoder) => decoder.finished(),
+            None => return Ok(()),
+        };
+        for Decoded { sound_name, generation, sounds } in decoded {
+            if generation != self.decode_generation {
+                continue;
+            }
+            // what was waiting on a failed sound is dropped, and later plays of it fail straight away rather than decoding it again
+            let waiting = self.decoding.remove(&sound_name).unwrap_or_default();
+            let sounds = match sounds {
+                Ok(sounds) => sounds,
+                Err(err) => {
+                    println!("Sound Worker failed to decode {:?} err -> {:?}", sound_name, err);
+                    self.decode_failures.insert(sound_name);
+                    continue;
+                },
+            };
+
+            let mut buffers = Vec::new();
+            for (sound, loudness) in sounds {
+                match self.buffer_sound(sound, loudness, self.multichannel(&sound_name), 1.0) {
+                    Ok(buffer) => buffers.push(buffer),
+                    Err(PreloadError::SoundProviderError(sp)) => return Err(sp),
+                    Err(err) => println!("Sound Worker failed to buffer {:?} err -> {:?}", sound_name, err),
+                }
+            }
+            if buffers.is_empty() {
+                println!("Sound Worker has nothing to play for {:?}, none of it could be buffered", sound_name);
+                self.decode_failures.insert(sound_name);
+                continue;
+            }
+            let longest = buffers.iter().map(|b| b.duration).fold(0.0, f32::max);
+            self.buffers.insert(sound_name, buffers);
+
+            for (sound_event, triggered_at) in waiting {
+                let offset = match self.decode_policy {
+                    DecodePolicy::Late => (time_ns.saturating_sub(triggered_at) as f32) / 1_000_000_000.0 * sound_event.pitch,
+                    _ => 0.0,
+                };
+                let looping = sound_event.loop_sound || self.manifest.sounds.get(&sound_event.name).map(|d| d.loop_sound).unwrap_or(false);
+                if offset >= longest && !looping {
+                    continue; // it'd be over already
+                }
+                match self.play_event_from(sound_event, None, offset) {
+                    Ok(_) => (),
+                    Err(SoundEventError::SoundProviderError(sp)) => return Err(sp),
+                    Err(err) => println!("Sound Worker had problem playing a decoded sound_event err -> {:?}", err),
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub fn ensure_buffers_queued(&mut self) -> PreloadResult<()> {
         for source in self.sources.streaming.iter_mut() {
             if source.current_binding.is_some() {
-                match source.ensure_buffers_queued(self.context, self.stream_buffer_duration) {
+                match source.ensure_buffers_queued(&self.backend, self.stream_buffer_duration) {
                     Ok(_) => (),
                     Err(err) => {
                         println!("received error while buffering streaming sources {:?}", err);
@@ -255,3 +626,23 @@ impl<'d> SoundContext<'d> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::buffer_offset;
+
+    #[test]
+    fn offsets_wrap_into_the_loop_region() {
+        // 4s with a 1s intro looping 1..3
+        let region = Some((1.0, 3.0));
+        assert_eq!(buffer_offset(0.5, 4.0, region, true), Some(0.5));
+        assert_eq!(buffer_offset(2.5, 4.0, region, true), Some(2.5));
+        assert_eq!(buffer_offset(3.5, 4.0, region, true), Some(1.5));
+        assert_eq!(buffer_offset(6.0, 4.0, region, true), Some(2.0));
+
+        // without a region it wraps whole, one shots past the end don't play
+        assert_eq!(buffer_offset(5.0, 4.0, None, true), Some(1.0));
+        assert_eq!(buffer_offset(5.0, 4.0, region, false), None);
+        assert_eq!(buffer_offset(0.0, 4.0, region, true), None);
+    }
+}
@@ -0,0 +1,111 @@
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::path::PathBuf;
+
+use super::assets::AssetSource;
+use super::load::{load_sound, Sound};
+use super::resample::resample;
+use super::loudness::{Loudness, measure};
+use {SoundName, LoadResult};
+
+// what happens to a one shot event whose sound is still decoding in the background
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DecodePolicy {
+    Drop, // it's not played at all
+    Delay, // it plays from the start once decoded
+    Late, // it plays once decoded, skipping what would've been heard in the meantime
+}
+
+impl Default for DecodePolicy {
+    fn default() -> DecodePolicy {
+        DecodePolicy::Delay
+    }
+}
+
+pub struct DecodeJob {
+    pub sound_name: SoundName,
+    pub paths: Vec<PathBuf>,
+    pub generation: u64, // results of jobs from before a purge are thrown away
+}
+
+pub struct Decoded {
+    pub sound_name: SoundName,
+    pub generation: u64,
+    pub sounds: LoadResult<Vec<(Sound, Loudness)>>, // resampled and measured, channel conversion is left to buffering
+}
+
+// decodes whole (static) sounds on its own threads so the worker isn't held up
+pub struct DecodePool {
+    jobs: Option<Sender<DecodeJob>>,
+    queued: Arc<Mutex<Receiver<DecodeJob>>>,
+    results: Receiver<Decoded>,
+}
+
+impl DecodePool {
+    pub fn new(threads: usize, assets: Arc<AssetSource>, resample_rate: Option<u32>) -> DecodePool {
+        let (job_tx, job_rx) = channel::<DecodeJob>();
+        let (result_tx, result_rx) = channel::<Decoded>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        for _ in 0..threads {
+            let job_rx = job_rx.clone();
+            let result_tx = result_tx.clone();
+            let assets = assets.clone();
+            thread::spawn(move || {
+                loop {
+                    // the lock is only held waiting for a job, not while decoding it
+                    let job = match job_rx.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let sounds = decode(&*assets, &job.paths, resample_rate);
+                    if result_tx.send(Decoded { sound_name: job.sound_name, generation: job.generation, sounds: sounds }).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        DecodePool {
+            jobs: Some(job_tx),
+            queued: job_rx,
+            results: result_rx,
+        }
+    }
+
+    pub fn submit(&self, job: DecodeJob) {
+        if let Some(ref jobs) = self.jobs {
+            let _ = jobs.send(job);
+        }
+    }
+
+    // everything decoded since the last call
+    pub fn finished(&self) -> Vec<Decoded> {
+        self.results.try_iter().collect()
+    }
+}
+
+impl Drop for DecodePool {
+    // queued jobs are thrown away, the threads aren't waited for, they finish the job in hand (its result goes nowhere) then exit
+    // so dropping the pool along with its context (e.g. rebuilding on another device) doesn't hold up the worker
+    fn drop(&mut self) {
+        self.jobs = None;
+        if let Ok(queued) = self.queued.lock() {
+            for _ in queued.try_iter() {}
+        }
+    }
+}
+
+fn decode(assets: &AssetSource, paths: &[PathBuf], resample_rate: Option<u32>) -> LoadResult<Vec<(Sound, Loudness)>> {
+    let mut sounds = Vec::new();
+    for path in paths {
+        let sound = load_sound(assets, path)?;
+        let loudness = measure(&sound);
+        sounds.push((match resample_rate {
+            Some(sample_rate) => resample(sound, sample_rate),
+            None => sound,
+        }, loudness));
+    }
+    Ok(sounds)
+}
@@ -0,0 +1,136 @@
+use {Gain, SoundEvent};
+use mix::BusMix;
+
+// standard EFX reverb parameters (AL_REVERB_*), the presets are from OpenAL Soft's efx-presets.h
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ReverbParams {
+    pub density: f32,
+    pub diffusion: f32,
+    pub gain: Gain,
+    pub gain_hf: Gain,
+    pub decay_time: f32,
+    pub decay_hf_ratio: f32,
+    pub reflections_gain: Gain,
+    pub reflections_delay: f32,
+    pub late_reverb_gain: Gain,
+    pub late_reverb_delay: f32,
+    pub air_absorption_gain_hf: Gain,
+    pub room_rolloff_factor: f32,
+    pub decay_hf_limit: bool,
+}
+
+impl ReverbParams {
+    pub fn generic() -> ReverbParams {
+        ReverbParams {
+            density: 1.0,
+            diffusion: 1.0,
+            gain: 0.3162,
+            gain_hf: 0.8913,
+            decay_time: 1.49,
+            decay_hf_ratio: 0.83,
+            reflections_gain: 0.05,
+            reflections_delay: 0.007,
+            late_reverb_gain: 1.2589,
+            late_reverb_delay: 0.011,
+            air_absorption_gain_hf: 0.9943,
+            room_rolloff_factor: 0.0,
+            decay_hf_limit: true,
+        }
+    }
+
+    pub fn room() -> ReverbParams {
+        ReverbParams {
+            density: 0.4287,
+            gain_hf: 0.5929,
+            decay_time: 0.4,
+            reflections_gain: 0.1503,
+            reflections_delay: 0.002,
+            late_reverb_gain: 1.0629,
+            late_reverb_delay: 0.003,
+            .. ReverbParams::generic()
+        }
+    }
+
+    pub fn hall() -> ReverbParams {
+        ReverbParams {
+            gain_hf: 0.5623,
+            decay_time: 3.92,
+            decay_hf_ratio: 0.7,
+            reflections_gain: 0.2427,
+            reflections_delay: 0.02,
+            late_reverb_gain: 0.9977,
+            late_reverb_delay: 0.029,
+            .. ReverbParams::generic()
+        }
+    }
+
+    pub fn cave() -> ReverbParams {
+        ReverbParams {
+            gain_hf: 1.0,
+            decay_time: 2.91,
+            decay_hf_ratio: 1.3,
+            reflections_gain: 0.5,
+            reflections_delay: 0.015,
+            late_reverb_gain: 0.7063,
+            late_reverb_delay: 0.022,
+            decay_hf_limit: false,
+            .. ReverbParams::generic()
+        }
+    }
+
+    pub fn outdoors() -> ReverbParams {
+        ReverbParams {
+            density: 0.2109,
+            gain_hf: 0.1,
+            decay_hf_ratio: 0.5,
+            reflections_gain: 0.0585,
+            reflections_delay: 0.179,
+            late_reverb_gain: 0.1089,
+            late_reverb_delay: 0.1,
+            .. ReverbParams::generic()
+        }
+    }
+
+    pub fn underwater() -> ReverbParams {
+        ReverbParams {
+            density: 0.3645,
+            gain_hf: 0.01,
+            decay_hf_ratio: 0.1,
+            reflections_gain: 0.5963,
+            late_reverb_gain: 7.0795,
+            .. ReverbParams::generic()
+        }
+    }
+}
+
+// mirrors AL_LOWPASS_GAIN/AL_LOWPASS_GAINHF, high frequencies get gain * gain_hf
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct LowPass {
+    pub gain: Gain,
+    pub gain_hf: Gain,
+}
+
+// one filter feeding another
+pub fn lowpass_series(a: Option<LowPass>, b: Option<LowPass>) -> Option<LowPass> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(LowPass { gain: a.gain * b.gain, gain_hf: a.gain_hf * b.gain_hf }),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+// per source routing, worked out from the event and its bus
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct SourceEffects {
+    pub reverb_send: Gain, // 0 is dry
+    pub lowpass: Option<LowPass>,
+}
+
+impl SourceEffects {
+    pub fn for_event(sound_event: &SoundEvent, bus_mix: &BusMix) -> SourceEffects {
+        SourceEffects {
+            reverb_send: sound_event.reverb_send * bus_mix.reverb_send,
+            lowpass: lowpass_series(sound_event.lowpass, bus_mix.lowpass),
+        }
+    }
+}
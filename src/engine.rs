@@ -2,6 +2,10 @@ use time;
 
 use {Listener, DistanceModel, SoundEvent, Gain, SoundName, SoundProviderResult};
 use super::context::{SoundContext};
+use super::backend::{SoundBackend, ContextAttributes, HrtfStatus};
+use super::effects::ReverbParams;
+use super::mix::{Buses, DuckRule, MixSnapshot};
+use super::channels::Multichannel;
 use super::source::SoundSourceLoan;
 use super::errors::*;
 use aphid::HashMap;
@@ -11,7 +15,9 @@ pub struct SoundRender {
     pub master_gain: f32, 
     pub sounds:Vec<SoundEvent>, 
     pub persistent_sounds:HashMap<String, SoundEvent>, 
-    pub listener: Listener
+    pub listener: Listener,
+    pub reverb: Option<ReverbParams>, // environment for this frame, None is dry
+    pub buses: Buses, // SoundEvent::bus names one of these
 }
 
 #[derive(Debug, Clone)]
@@ -20,9 +26,36 @@ pub enum SoundEngineUpdate {
     DistanceModel(DistanceModel),
     Render(SoundRender),
     Clear, // unbind all sources, destroy all buffers,
+    Device(Option<String>), // switch output device (None for default), handled by the worker
+    Hrtf(bool, Option<String>), // enable/disable HRTF, optionally with one of the reported profiles
+    DuckRules(Vec<DuckRule>), // replaces the ducking rules, evaluated every tick
+    PushSnapshot(String, MixSnapshot, f32), // named, with the transition in seconds
+    PopSnapshot(String, f32),
+    Multichannel(SoundName, Multichannel), // how a sound's channels are played, it's reloaded on next use
     Stop,
 }
 
+// sent back from the worker, poll them with SoundWorker::reports
+#[derive(Debug, Clone)]
+pub enum SoundEngineReport {
+    Device(String), // the output device in use
+    DeviceLost(String), // the worker is rebuilding on another device
+    DeviceFailed(String), // couldn't be opened or used, the worker falls back to the default device or tries it again
+    ContextAttributes(ContextAttributes), // what the device granted
+    HrtfProfiles(Vec<String>),
+    Hrtf(HrtfStatus),
+    HrtfProfileUnavailable(String), // requested with SoundEngineUpdate::Hrtf but not one of the device's, nothing was changed
+    ResourcesUnwatched(String), // the resources path couldn't be watched (why), changes to it aren't picked up
+}
+
+// where a persistent sound was, so it can carry on after the context is rebuilt
+#[derive(Debug, Clone)]
+pub struct SuspendedSound {
+    pub name: String,
+    pub sound_event: SoundEvent,
+    pub offset: f32,
+}
+
 // we need our state of what's already persisted, loans etc.
 
 pub struct SoundEngine {
@@ -30,6 +63,8 @@ pub struct SoundEngine {
     // some notion of existing sounds
     pub last_render_time: u64,
     pub loans : HashMap<String, SoundSourceLoan>,
+    pub resume_points : HashMap<String, (SuspendedSound, u64)>, // as of the render time it was recorded at
+    pub reports : Vec<SoundEngineReport>, // drained and sent back by the worker
 }
 
 impl SoundEngine {
@@ -37,10 +72,47 @@ impl SoundEngine {
         SoundEngine {
             last_render_time: time::precise_time_ns(),
             loans: HashMap::default(),
+            resume_points: HashMap::default(),
+            reports: Vec::new(),
+        }
+    }
+
+    // forgets all loans (their sources are about to go away) and returns where the persistent sounds would be by time_ns
+    pub fn suspend(&mut self, time_ns: u64) -> Vec<SuspendedSound> {
+        self.loans.clear();
+        self.resume_points.drain().map(|(_, (mut suspended, recorded_at))| {
+            let elapsed = (time_ns.saturating_sub(recorded_at) as f32) / 1_000_000_000.0;
+            suspended.offset += elapsed * suspended.sound_event.pitch;
+            suspended
+        }).collect()
+    }
+
+    pub fn resume<B: SoundBackend>(&mut self, context: &mut SoundContext<B>, suspended: Vec<SuspendedSound>) -> SoundProviderResult<()> {
+        for sound in suspended {
+            match context.play_event_from(sound.sound_event.clone(), None, sound.offset) {
+                Ok(loan) => {
+                    self.loans.insert(sound.name, loan);
+                },
+                Err(SoundEventError::SoundProviderError(sp)) => return Err(sp),
+                Err(err) => println!("Sound Worker had problem resuming sound_event {:?} err -> {:?}", sound.sound_event, err),
+            }
         }
+        Ok(())
     }
 
-    pub fn process(&mut self, context: &mut SoundContext, update:SoundEngineUpdate) -> SoundProviderResult<bool> { // book is over clean shutdown
+    // called regularly by the worker (and every step of an offline render) so the mix moves on between updates
+    pub fn tick<B: SoundBackend>(&mut self, context: &mut SoundContext<B>, time_ns: u64) -> SoundProviderResult<()> {
+        context.sources.check_bindings()?;
+        context.finish_decodes(time_ns)?;
+        context.update_mix(time_ns)
+    }
+
+    pub fn process<B: SoundBackend>(&mut self, context: &mut SoundContext<B>, update:SoundEngineUpdate) -> SoundProviderResult<bool> { // book is over clean shutdown
+        self.process_at(context, update, time::precise_time_ns())
+    }
+
+    // time_ns is the engine's notion of now, offline rendering supplies script time instead of the wall clock
+    pub fn process_at<B: SoundBackend>(&mut self, context: &mut SoundContext<B>, update:SoundEngineUpdate, time_ns: u64) -> SoundProviderResult<bool> {
         use self::SoundEngineUpdate::*;
         let should_continue = match update {
             Preload(sounds) => {
@@ -60,7 +132,8 @@ impl SoundEngine {
                 true
             },
             Render(render) => {
-                // { master_gain, sounds, persistent_sounds, listener }
+                // { master_gain, sounds, persistent_sounds, listener, reverb, buses }
+                self.last_render_time = time_ns;
                 try!(context.sources.check_bindings());
                 match context.ensure_buffers_queued() {
                     Ok(_) => (),
@@ -73,10 +146,17 @@ impl SoundEngine {
                 if context.listener != render.listener {
                     try!(context.set_listener(render.listener));
                 }
+                if context.reverb != render.reverb {
+                    context.set_reverb(render.reverb)?;
+                }
+                if context.buses != render.buses {
+                    context.set_buses(render.buses)?;
+                }
 
                 for sound_event in render.sounds {
-                    match context.play_event(sound_event.clone(), None) {
+                    match context.trigger_event(sound_event.clone(), time_ns) {
                         Ok(_) => (),
+                        Err(SoundEventError::DecodeFailed) => (), // reported when it failed
                         Err(SoundEventError::SoundProviderError(sp)) => return Err(sp),
                         Err(err) => println!("Sound Worker had problem playing sound_event {:?} err -> {:?}", sound_event, err),
                     }
@@ -89,17 +169,54 @@ impl SoundEngine {
                         Ok(new_loan) => {
                             self.loans.insert(name, new_loan);        
                         },
+                        Err(SoundEventError::Decoding) => (), // tried again next render
+                        Err(SoundEventError::DecodeFailed) => (), // reported when it failed
                         Err(SoundEventError::SoundProviderError(sp)) => return Err(sp),
                         Err(err) => println!("Sound Worker had problem playing sound_event {:?} err -> {:?}", sound_event, err),
                     }
                 }
 
+                let mut resume_points = HashMap::default();
+                for (name, loan) in self.loans.iter() {
+                    if let Some(source) = context.sources.for_loan(*loan) {
+                        if let (Some(sound_event), Ok(offset)) = (source.sound_event().cloned(), source.offset()) {
+                            let suspended = SuspendedSound { name: name.clone(), sound_event: sound_event, offset: offset };
+                            resume_points.insert(name.clone(), (suspended, time_ns));
+                        }
+                    }
+                }
+                self.resume_points = resume_points;
+
                 true  
             },
             Clear => {
                 try!(context.purge());
                 true
             },
+            Device(_) => true, // the worker rebuilds the context, nothing to do here
+            DuckRules(rules) => {
+                context.set_duck_rules(rules);
+                true
+            },
+            PushSnapshot(name, snapshot, transition) => {
+                context.push_snapshot(name, snapshot, transition);
+                true
+            },
+            PopSnapshot(name, transition) => {
+                context.pop_snapshot(&name, transition);
+                true
+            },
+            Multichannel(sound_name, multichannel) => {
+                context.set_multichannel(sound_name, multichannel);
+                true
+            },
+            Hrtf(enabled, profile) => {
+                if !context.set_hrtf(enabled, profile.clone())? {
+                    self.reports.push(SoundEngineReport::HrtfProfileUnavailable(profile.unwrap_or_default()));
+                }
+                self.reports.push(SoundEngineReport::Hrtf(context.backend.hrtf_status()?));
+                true
+            },
             Stop => {
                 try!(context.purge());
                 false
@@ -108,3 +225,214 @@ impl SoundEngine {
         Ok(should_continue)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    use hound;
+    use rand::XorShiftRng;
+
+    use {Listener, SoundEvent, LoadResult, PreloadResult};
+    use super::{SoundEngine, SoundEngineUpdate, SoundEngineReport, SoundRender};
+    use context::{SoundContext, create_sound_context};
+    use backend::{NullBackend, SourceState, HrtfStatus};
+    use assets::AssetSource;
+    use load::SoundReader;
+    use decode::DecodePool;
+    use errors::SoundEventError;
+    use aphid::HashMap;
+
+    // a tenth of a second of mono wav
+    fn tone() -> Vec<u8> {
+        let spec = hound::WavSpec { channels: 1, sample_rate: 44100, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut bytes = Cursor::new(Vec::new());
+        {
+            let mut writer = hound::WavWriter::new(&mut bytes, spec).unwrap();
+            for i in 0..4410 {
+                writer.write_sample(((i % 100) * 100) as i16).unwrap();
+            }
+            writer.finalize().unwrap();
+        }
+        bytes.into_inner()
+    }
+
+    // every sound name is the same tone (but the missing ones), and there's no manifest
+    struct ToneAssets;
+
+    impl AssetSource for ToneAssets {
+        fn resolve(&self, sound_name: &str, extensions: &[String]) -> Option<PathBuf> {
+            if extensions.iter().any(|e| e == "wav") && !sound_name.starts_with("missing") {
+                Some(PathBuf::from(format!("{}.wav", sound_name)))
+            } else {
+                None
+            }
+        }
+
+        fn variants(&self, _sound_name: &str, _extensions: &[String]) -> PreloadResult<Vec<PathBuf>> {
+            Ok(vec![])
+        }
+
+        fn open(&self, _path: &Path) -> LoadResult<SoundReader> {
+            Ok(Box::new(Cursor::new(tone())))
+        }
+
+        fn size(&self, _path: &Path) -> LoadResult<u64> {
+            Ok(tone().len() as u64)
+        }
+    }
+
+    fn context() -> SoundContext<NullBackend> {
+        let mut context = create_sound_context(NullBackend, Arc::new(ToneAssets), &["wav".to_string()], XorShiftRng::new_unseeded(), 1_000_000, 1.0, None);
+        context.create(4, 1).unwrap();
+        context
+    }
+
+    fn event(name: &str) -> SoundEvent {
+        SoundEvent {
+            name: name.to_string(),
+            position: [0.0, 0.0, 0.0],
+            gain: 1.0,
+            pitch: 1.0,
+            attenuation: 1.0,
+            loop_sound: false,
+            reverb_send: 0.0,
+            lowpass: None,
+            bus: None,
+        }
+    }
+
+    fn render(sounds: Vec<SoundEvent>, persistent_sounds: Vec<(&str, SoundEvent)>) -> SoundEngineUpdate {
+        SoundEngineUpdate::Render(SoundRender {
+            master_gain: 1.0,
+            sounds: sounds,
+            persistent_sounds: persistent_sounds.into_iter().map(|(name, sound_event)| (name.to_string(), sound_event)).collect(),
+            listener: Listener::default(),
+            reverb: None,
+            buses: HashMap::default(),
+        })
+    }
+
+    fn bound(context: &SoundContext<NullBackend>) -> usize {
+        context.sources.sources.iter().filter(|s| s.current_binding.is_some()).count()
+    }
+
+    #[test]
+    fn render_plays_one_shot_events() {
+        let mut context = context();
+        let mut engine = SoundEngine::new();
+
+        let mut quiet = event("beep");
+        quiet.gain = 0.5;
+        assert!(engine.process(&mut context, render(vec![event("beep"), quiet], vec![])).unwrap());
+
+        assert_eq!(bound(&context), 2);
+        assert!(context.buffers.contains_key("beep"));
+        let first = &context.sources.sources[0].inner;
+        assert_eq!(first.state, SourceState::Playing);
+        assert!(first.buffer.is_some());
+        assert_eq!(first.gain, 1.0);
+        assert_eq!(context.sources.sources[1].inner.gain, 0.5);
+        assert!(engine.loans.is_empty());
+    }
+
+    #[test]
+    fn persistent_sounds_keep_their_loan() {
+        let mut context = context();
+        let mut engine = SoundEngine::new();
+
+        let mut hum = event("hum");
+        hum.loop_sound = true;
+        engine.process(&mut context, render(vec![], vec![("hum", hum.clone())])).unwrap();
+        let loan = engine.loans["hum"];
+
+        hum.gain = 0.25;
+        engine.process(&mut context, render(vec![], vec![("hum", hum)])).unwrap();
+        let reloan = engine.loans["hum"];
+
+        assert_eq!((reloan.source_id, reloan.event_id), (loan.source_id, loan.event_id));
+        assert_eq!(bound(&context), 1);
+        let source = &context.sources.sources[loan.source_id].inner;
+        assert_eq!(source.plays, 1); // updated in place, not restarted
+        assert_eq!(source.gain, 0.25);
+        assert!(source.looping);
+    }
+
+    #[test]
+    fn finished_sources_are_reused() {
+        let mut context = context();
+        let mut engine = SoundEngine::new();
+
+        engine.process(&mut context, render(vec![event("beep")], vec![])).unwrap();
+        context.sources.sources[0].inner.state = SourceState::Stopped;
+        engine.process(&mut context, render(vec![event("boop")], vec![])).unwrap();
+
+        assert_eq!(bound(&context), 1);
+        assert_eq!(context.sources.sources[0].current_binding.as_ref().map(|b| b.sound_event.name.as_str()), Some("boop"));
+    }
+
+    #[test]
+    fn clear_unbinds_sources_and_drops_buffers() {
+        let mut context = context();
+        let mut engine = SoundEngine::new();
+
+        engine.process(&mut context, render(vec![event("beep")], vec![("hum", event("hum"))])).unwrap();
+        assert!(engine.process(&mut context, SoundEngineUpdate::Clear).unwrap());
+
+        assert_eq!(bound(&context), 0);
+        assert!(context.buffers.is_empty());
+        assert!(context.sources.sources.iter().all(|s| s.inner.state == SourceState::Stopped && s.inner.buffer.is_none()));
+
+        // and carries on, reloading what it needs
+        engine.process(&mut context, render(vec![event("beep")], vec![])).unwrap();
+        assert_eq!(bound(&context), 1);
+        assert!(context.buffers.contains_key("beep"));
+    }
+
+    #[test]
+    fn stop_purges_and_ends_processing() {
+        let mut context = context();
+        let mut engine = SoundEngine::new();
+
+        engine.process(&mut context, render(vec![event("beep")], vec![("hum", event("hum"))])).unwrap();
+        assert!(!engine.process(&mut context, SoundEngineUpdate::Stop).unwrap());
+
+        assert_eq!(bound(&context), 0);
+        assert!(context.buffers.is_empty());
+        assert!(context.sources.sources.iter().all(|s| s.inner.state == SourceState::Stopped));
+    }
+
+    #[test]
+    fn unknown_hrtf_profiles_are_reported() {
+        let mut context = context();
+        let mut engine = SoundEngine::new();
+
+        engine.process(&mut context, SoundEngineUpdate::Hrtf(true, Some("kemar".to_string()))).unwrap();
+
+        match engine.reports.as_slice() {
+            &[SoundEngineReport::HrtfProfileUnavailable(ref profile), SoundEngineReport::Hrtf(HrtfStatus::Unsupported)] => assert_eq!(profile, "kemar"),
+            reports => panic!("unexpected reports {:?}", reports),
+        }
+        assert!(context.hrtf.is_none());
+    }
+
+    #[test]
+    fn missing_sounds_are_no_sounds_rather_than_decoded() {
+        let mut context = context();
+        context.decoder = Some(DecodePool::new(1, Arc::new(ToneAssets), None));
+
+        match context.play_event(event("missing_door"), None) {
+            Err(SoundEventError::NoSounds) => (),
+            other => panic!("a missing sound should be NoSounds, not {:?}", other.map(|_| ())),
+        }
+        assert!(context.decoding.is_empty() && context.decode_failures.is_empty());
+
+        match context.play_event(event("door"), None) {
+            Err(SoundEventError::Decoding) => (),
+            other => panic!("a sound that's there should be decoding, not {:?}", other.map(|_| ())),
+        }
+    }
+
+}
@@ -3,14 +3,35 @@ pub mod load;
 pub mod context;
 pub mod source;
 pub mod worker;
+pub mod backend;
+pub mod openal;
+pub mod mixer;
+pub mod render;
+pub mod effects;
+pub mod mix;
+pub mod channels;
+pub mod resample;
+pub mod assets;
+pub mod manifest;
+pub mod select;
+pub mod decode;
+pub mod loudness;
 
 extern crate alto;
-// extern crate ogg;
+extern crate ogg;
 extern crate lewton;
 extern crate time;
 extern crate notify;
 extern crate aphid;
 extern crate rand;
+extern crate hound;
+extern crate claxon;
+#[cfg(feature = "opus")]
+extern crate opus;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
 
 pub type Vec3 = [f32; 3];
 
@@ -20,6 +41,8 @@ pub const VEC3_ZERO : Vec3 = [0.0, 0.0, 0.0];
 
 pub type SoundName = String;
 
+pub type BusName = String;
+
 pub type SoundEventId = u64; 
 
 pub type Gain = f32;
@@ -34,6 +57,9 @@ pub struct SoundEvent {
     pub pitch: f32,
     pub attenuation: f32, // unsure if this should be bool for relative, or an optional rolloff factor (within the context distance model)
     pub loop_sound: bool,
+    pub reverb_send: Gain, // how much goes to the render's reverb, 0 is dry
+    pub lowpass: Option<effects::LowPass>, // direct path filter e.g. for occlusion, None is unfiltered
+    pub bus: Option<BusName>, // None plays straight through the master gain
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -58,8 +84,9 @@ impl Listener {
 pub type LoadResult<T> = Result<T, errors::LoadError>;
 pub type PreloadResult<T> = Result<T, errors::PreloadError>;
 pub type SoundEventResult<T> = Result<T, errors::SoundEventError>;
-pub type SoundProviderResult<T> = Result<T, alto::AltoError>;
-pub type WorkerResult<T> = Result<T, alto::AltoError>;
+pub type SoundProviderResult<T> = Result<T, errors::SoundProviderError>;
+pub type WorkerResult<T> = Result<T, errors::SoundProviderError>;
+pub type RenderResult<T> = Result<T, errors::RenderError>;
 
 use std::fs;
 use std::path::{PathBuf, Path};
@@ -81,12 +108,49 @@ pub mod errors {
     use alto;
     use std::path::PathBuf;
     use lewton;
+    use hound;
+    use claxon;
+    use ogg;
+    #[cfg(feature = "opus")]
+    use opus;
+    use toml;
     use std::io;
 
+    // anything a backend can fail with, these halt the worker
+    #[derive(Debug)]
+    pub enum SoundProviderError {
+        Alto(alto::AltoError),
+        InvalidOperation(&'static str),
+    }
+
+    impl From<alto::AltoError> for SoundProviderError {
+        fn from(val: alto::AltoError) -> SoundProviderError {
+            SoundProviderError::Alto(val)
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum RenderError {
+        SoundProviderError(SoundProviderError),
+        WriteWavError(hound::Error),
+    }
+
+    impl From<SoundProviderError> for RenderError {
+        fn from(val: SoundProviderError) -> RenderError {
+            RenderError::SoundProviderError(val)
+        }
+    }
+
+    impl From<hound::Error> for RenderError {
+        fn from(val: hound::Error) -> RenderError {
+            RenderError::WriteWavError(val)
+        }
+    }
+
     #[derive(Debug)]
     pub enum PreloadError {
         LoadError(LoadError),
-        SoundProviderError(alto::AltoError), // this is a dupe at this point ... hrm
+        SoundProviderError(SoundProviderError), // this is a dupe at this point ... hrm
     }
 
     impl From<LoadError> for PreloadError {
@@ -95,8 +159,8 @@ pub mod errors {
         }
     }
 
-    impl From<alto::AltoError> for PreloadError {
-        fn from(val: alto::AltoError) -> PreloadError {
+    impl From<SoundProviderError> for PreloadError {
+        fn from(val: SoundProviderError) -> PreloadError {
             PreloadError::SoundProviderError(val)
         }
     }
@@ -112,16 +176,26 @@ pub mod errors {
         FileDoesntExist,
         FileReadError(io::Error),
         ReadOggError(lewton::VorbisError),
+        ReadWavError(hound::Error),
+        ReadFlacError(claxon::Error),
+        ReadOggPacketError(ogg::OggReadError), // the container, ReadOggError is vorbis
+        #[cfg(feature = "opus")]
+        ReadOpusError(opus::Error),
+        InvalidOpusHeader,
+        OpusDisabled, // an opus file, without the opus feature
         TooManyChannels,
+        ReadManifestError(toml::de::Error),
     }
 
     #[derive(Debug)]
     pub enum SoundEventError {
         LoadSoundError(LoadError), // recoverable
-        SoundProviderError(alto::AltoError),
+        SoundProviderError(SoundProviderError),
         NoFreeStaticSource,
         NoFreeStreamingSource,
         NoSounds,
+        Decoding, // the sound's being decoded in the background, not an error as such
+        DecodeFailed, // it was decoded in the background and that failed (and was reported), it's not tried again until a purge
     }
 
     impl From<LoadError> for SoundEventError {
@@ -130,8 +204,8 @@ pub mod errors {
         }
     }
 
-    impl From<alto::AltoError> for SoundEventError {
-        fn from(val: alto::AltoError) -> SoundEventError {
+    impl From<SoundProviderError> for SoundEventError {
+        fn from(val: SoundProviderError) -> SoundEventError {
             SoundEventError::SoundProviderError(val)
         }
     }
@@ -1,90 +1,696 @@
 use lewton::inside_ogg::OggStreamReader;
+use lewton::samples::InterleavedSamples;
+use hound::{WavReader, SampleFormat};
+use claxon::{FlacReader, FlacIntoSamples};
+use claxon::metadata::StreamInfo;
+use ogg::PacketReader;
+#[cfg(feature = "opus")]
+use opus;
 
-use std::fs;
-use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Cursor};
 use std::path::{PathBuf, Path};
 
 use super::LoadResult;
+use super::assets::AssetSource;
+use super::channels::{MAX_CHANNELS, vorbis_to_wav_order};
 use super::errors::*;
 
 
+// samples are f32 in -1.0..1.0 from decoding all the way to the backend
 #[derive(Clone, Debug)]
 pub struct Sound {
-    pub data : Vec<i16>,
+    pub data : Vec<f32>,
     pub sample_rate: u32,
     pub channels: u8,
+    pub loop_region: Option<LoopRegion>,
+}
+
+// from LOOPSTART/LOOPLENGTH comments, in frames, a looping sound plays its intro once then repeats start..end
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoopRegion {
+    pub start: u64,
+    pub end: Option<u64>, // None loops at the end of the sound
+}
+
+impl LoopRegion {
+    // vorbis comment style (key, value) pairs, keys are case insensitive
+    pub fn from_comments<'a, I: IntoIterator<Item=(&'a str, &'a str)>>(comments: I) -> Option<LoopRegion> {
+        let (mut start, mut length) = (None, None);
+        for (key, value) in comments {
+            if key.eq_ignore_ascii_case("LOOPSTART") {
+                start = value.trim().parse::<u64>().ok();
+            } else if key.eq_ignore_ascii_case("LOOPLENGTH") {
+                length = value.trim().parse::<u64>().ok();
+            }
+        }
+        // a length that runs off the end of a u64 is garbage, the whole region goes
+        match (start, length) {
+            (Some(start), Some(length)) => start.checked_add(length).map(|end| LoopRegion { start: start, end: Some(end) }),
+            (Some(start), None) => Some(LoopRegion { start: start, end: None }),
+            (None, _) => None,
+        }
+    }
+
+    // the same region at another sample rate, saturating rather than overflowing
+    pub fn scaled(&self, from: u32, to: u32) -> LoopRegion {
+        if from == 0 {
+            return *self;
+        }
+        let scale = |frame: u64| {
+            let scaled = (frame as u128) * (to as u128) / (from as u128);
+            if scaled > ::std::u64::MAX as u128 { ::std::u64::MAX } else { scaled as u64 }
+        };
+        LoopRegion { start: scale(self.start), end: self.end.map(scale) }
+    }
+
+    // (start, end) within a sound of frames, None when that leaves nothing to loop
+    pub fn frames(&self, frames: u64) -> Option<(u64, u64)> {
+        let end = self.end.unwrap_or(frames).min(frames);
+        if self.start < end {
+            Some((self.start, end))
+        } else {
+            None
+        }
+    }
 }
 
 impl Sound {
     pub fn duration(&self) -> f32 {
-        (self.data.len() as f32) / (self.sample_rate as f32)
+        (self.data.len() as f32) / (self.channels as f32) / (self.sample_rate as f32)
+    }
+}
+
+// anything a sound can be decoded from, a file, a region of a pack file, bytes in the binary
+pub trait SoundRead : Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> SoundRead for T {}
+
+pub type SoundReader = Box<SoundRead>;
+
+// for include_bytes! sounds
+pub fn embedded_reader(bytes: &'static [u8]) -> SoundReader {
+    Box::new(Cursor::new(bytes))
+}
+
+// a decoder that's read a chunk at a time, one per format
+pub enum SoundStream {
+    Ogg(OggStreamReader<SoundReader>),
+    Wav(WavReader<BufReader<SoundReader>>),
+    Flac(FlacIntoSamples<BufReader<SoundReader>>, StreamInfo, Option<LoopRegion>),
+    #[cfg(feature = "opus")]
+    Opus(OpusStream),
+}
+
+impl SoundStream {
+    pub fn channels(&self) -> u8 {
+        match *self {
+            SoundStream::Ogg(ref reader) => reader.ident_hdr.audio_channels,
+            SoundStream::Wav(ref reader) => reader.spec().channels as u8,
+            SoundStream::Flac(_, ref info, _) => info.channels as u8,
+            #[cfg(feature = "opus")]
+            SoundStream::Opus(ref stream) => stream.channels,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        match *self {
+            SoundStream::Ogg(ref reader) => reader.ident_hdr.audio_sample_rate,
+            SoundStream::Wav(ref reader) => reader.spec().sample_rate,
+            SoundStream::Flac(_, ref info, _) => info.sample_rate,
+            #[cfg(feature = "opus")]
+            SoundStream::Opus(_) => OPUS_SAMPLE_RATE,
+        }
+    }
+
+    pub fn loop_region(&self) -> Option<LoopRegion> {
+        match *self {
+            SoundStream::Ogg(ref reader) => vorbis_loop_region(reader),
+            SoundStream::Wav(_) => None,
+            SoundStream::Flac(_, _, loop_region) => loop_region,
+            #[cfg(feature = "opus")]
+            SoundStream::Opus(ref stream) => stream.loop_region,
+        }
+    }
+
+    // appends interleaved samples until data holds at least samples (or the stream ends), returns how many were read
+    pub fn read(&mut self, data: &mut Vec<f32>, samples: usize) -> Result<usize, LoadErrorReason> {
+        match *self {
+            SoundStream::Ogg(ref mut reader) => {
+                let mut samples_read : usize = 0;
+                while data.len() < samples {
+                    if let Some(mut packet) = read_vorbis_packet(reader).map_err(LoadErrorReason::ReadOggError)? {
+                        vorbis_to_wav_order(&mut packet, reader.ident_hdr.audio_channels);
+                        samples_read += packet.len();
+                        data.extend(&packet);
+                    } else {
+                        break;
+                    }
+                }
+                Ok(samples_read)
+            },
+            SoundStream::Wav(ref mut reader) => {
+                let wanted = samples.saturating_sub(data.len());
+                read_wav_samples(reader, data, wanted).map_err(LoadErrorReason::ReadWavError)
+            },
+            SoundStream::Flac(ref mut flac_samples, ref info, _) => {
+                let wanted = samples.saturating_sub(data.len());
+                read_flac_samples(flac_samples, info.bits_per_sample, data, wanted).map_err(LoadErrorReason::ReadFlacError)
+            },
+            #[cfg(feature = "opus")]
+            SoundStream::Opus(ref mut stream) => {
+                let mut samples_read : usize = 0;
+                while data.len() < samples {
+                    if let Some(packet) = stream.read_packet()? {
+                        samples_read += packet.len();
+                        data.extend(&packet);
+                    } else {
+                        break;
+                    }
+                }
+                Ok(samples_read)
+            },
+        }
     }
 }
 
 pub enum LoadedSound {
     Static(Vec<Sound>),
-    Streaming(OggStreamReader<File>),
+    Streaming(SoundStream),
+}
+
+enum SoundFormat {
+    Ogg, // vorbis
+    Opus,
+    Wav,
+    Flac,
+}
+
+// from the first bytes, anything unrecognised is treated as ogg and the codec inside is checked
+// the reader is left where it started
+fn sound_format(reader: &mut SoundReader) -> Result<SoundFormat, LoadErrorReason> {
+    let start = reader.seek(SeekFrom::Current(0)).map_err(LoadErrorReason::FileReadError)?;
+    let mut magic = [0u8; 4];
+    let format = match read_magic(reader, &mut magic).map_err(LoadErrorReason::FileReadError)? {
+        4 if &magic == b"RIFF" => SoundFormat::Wav,
+        4 if &magic == b"fLaC" => SoundFormat::Flac,
+        _ => {
+            reader.seek(SeekFrom::Start(start)).map_err(LoadErrorReason::FileReadError)?;
+            ogg_codec(reader)?
+        },
+    };
+    reader.seek(SeekFrom::Start(start)).map_err(LoadErrorReason::FileReadError)?;
+    Ok(format)
+}
+
+// like read_exact but a short file isn't an error
+fn read_magic(reader: &mut SoundReader, magic: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < magic.len() {
+        match reader.read(&mut magic[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+// the first packet of the stream identifies the codec
+fn ogg_codec(reader: &mut SoundReader) -> Result<SoundFormat, LoadErrorReason> {
+    let mut packets = PacketReader::new(reader);
+    let first = packets.read_packet().map_err(LoadErrorReason::ReadOggPacketError)?;
+    match first {
+        Some(ref packet) if packet.data.starts_with(OPUS_HEAD) => Ok(SoundFormat::Opus),
+        _ => Ok(SoundFormat::Ogg),
+    }
+}
+
+// opus always decodes at 48kHz whatever the header's input rate says
+pub const OPUS_SAMPLE_RATE : u32 = 48000;
+
+const OPUS_HEAD : &'static [u8] = b"OpusHead";
+#[cfg(feature = "opus")]
+const OPUS_TAGS : &'static [u8] = b"OpusTags";
+
+// a vorbis comment header without its packet type and framing bit (as OpusTags has it), (key, value) pairs
+#[cfg(feature = "opus")]
+fn parse_comments(data: &[u8]) -> Vec<(String, String)> {
+    let read_u32 = |at: usize| data.get(at..at.saturating_add(4)).map(|b| (b[0] as usize) | ((b[1] as usize) << 8) | ((b[2] as usize) << 16) | ((b[3] as usize) << 24));
+    let mut comments = Vec::new();
+
+    let vendor_length = match read_u32(0) {
+        Some(length) => length,
+        None => return comments,
+    };
+    let mut at = 4usize.saturating_add(vendor_length);
+    let count = match read_u32(at) {
+        Some(count) => count,
+        None => return comments,
+    };
+    at = at.saturating_add(4);
+
+    for _ in 0..count {
+        let length = match read_u32(at) {
+            Some(length) => length,
+            None => break,
+        };
+        at = at.saturating_add(4);
+        let comment = match data.get(at..at.saturating_add(length)) {
+            Some(comment) => String::from_utf8_lossy(comment).into_owned(),
+            None => break,
+        };
+        at = at.saturating_add(length);
+        if let Some(split) = comment.find('=') {
+            comments.push((comment[..split].to_string(), comment[(split + 1)..].to_string()));
+        }
+    }
+    comments
+}
+
+// 120ms, the longest an opus packet can be
+#[cfg(feature = "opus")]
+const OPUS_MAX_PACKET_FRAMES : usize = 5760;
+
+// what's used of an OpusHead packet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OpusHead {
+    pub channels: u8,
+    pub pre_skip: u64, // frames at the start that are decoder warm up, not audio
+    pub gain: f32, // the header's output gain
+}
+
+impl OpusHead {
+    // only single mono or stereo streams are accepted, see single_stream
+    pub fn parse(head: &[u8]) -> Result<OpusHead, LoadErrorReason> {
+        if head.len() < 19 || !head.starts_with(OPUS_HEAD) || !single_stream(head) {
+            return Err(LoadErrorReason::InvalidOpusHeader);
+        }
+        let output_gain = ((head[16] as u16) | ((head[17] as u16) << 8)) as i16; // Q7.8 dB
+        Ok(OpusHead {
+            channels: head[9],
+            pre_skip: (head[10] as u64) | ((head[11] as u64) << 8),
+            gain: 10.0_f32.powf((output_gain as f32) / 256.0 / 20.0),
+        })
+    }
+
+    // (from, to) of frames decoded from granule position start that are audio, past the pre skip and before end
+    // (the last page's granule position, where the stream's audio really ends)
+    pub fn audio_frames(&self, start: u64, frames: u64, end: Option<u64>) -> (u64, u64) {
+        let decoded = start + frames;
+        let end = end.map(|end| end.min(decoded)).unwrap_or(decoded).max(start);
+        (self.pre_skip.max(start).min(end) - start, end - start)
+    }
+}
+
+// whether the head (at least 19 bytes) describes one mono or stereo stream, the only layout a plain decoder handles
+// family 0 always is (given 1 or 2 channels), other families only with a table of a single stream mapped straight through
+// surround opus needs a multistream decoder which we don't have
+fn single_stream(head: &[u8]) -> bool {
+    let channels = head[9] as usize;
+    let family = head[18];
+    if channels == 0 || channels > 2 {
+        return false;
+    }
+    if family == 0 {
+        return true;
+    }
+    match head.get(19..(21 + channels)) {
+        Some(table) => {
+            let (streams, coupled, mapping) = (table[0] as usize, table[1] as usize, &table[2..]);
+            streams == 1 && coupled == channels - 1 && mapping.iter().enumerate().all(|(i, &m)| m as usize == i)
+        },
+        None => false,
+    }
 }
 
-fn file_size(path: &Path) -> LoadResult<u64> {
-    let meta_data = fs::metadata(path).map_err(|ioe| LoadError { path: path.to_path_buf(), reason: LoadErrorReason::FileReadError(ioe) })?;
-    Ok(meta_data.len())
+// ogg opus of a single mono or stereo stream
+#[cfg(feature = "opus")]
+pub struct OpusStream {
+    packets: PacketReader<SoundReader>,
+    decoder: opus::Decoder,
+    pub channels: u8,
+    head: OpusHead,
+    position: u64, // frames decoded so far, pre skip included (the granule position)
+    scratch: Vec<f32>,
+    pub loop_region: Option<LoopRegion>, // from OpusTags, in 48kHz frames
 }
 
-fn open_file(path:&Path) -> LoadResult<File> {
-   File::open(path).map_err(|ioe| LoadError { path: path.to_path_buf(), reason: LoadErrorReason::FileReadError(ioe) }) 
+#[cfg(feature = "opus")]
+impl OpusStream {
+    pub fn new(reader: SoundReader) -> Result<OpusStream, LoadErrorReason> {
+        let mut packets = PacketReader::new(reader);
+        let head = packets.read_packet().map_err(LoadErrorReason::ReadOggPacketError)?.ok_or(LoadErrorReason::InvalidOpusHeader)?;
+        let head = OpusHead::parse(&head.data)?;
+
+        // only the loop comments are of any use in the tags
+        let tags = packets.read_packet().map_err(LoadErrorReason::ReadOggPacketError)?;
+        let loop_region = match tags {
+            Some(ref tags) if tags.data.starts_with(OPUS_TAGS) => LoopRegion::from_comments(parse_comments(&tags.data[OPUS_TAGS.len()..]).iter().map(|&(ref key, ref value)| (key.as_str(), value.as_str()))),
+            _ => None,
+        };
+
+        let decoder_channels = if head.channels == 1 { opus::Channels::Mono } else { opus::Channels::Stereo };
+        let decoder = opus::Decoder::new(OPUS_SAMPLE_RATE, decoder_channels).map_err(LoadErrorReason::ReadOpusError)?;
+
+        Ok(OpusStream {
+            packets: packets,
+            decoder: decoder,
+            channels: head.channels,
+            head: head,
+            position: 0,
+            scratch: vec![0.0; OPUS_MAX_PACKET_FRAMES * head.channels as usize],
+            loop_region: loop_region,
+        })
+    }
+
+    // interleaved samples of the next packet with pre skip and end trimming applied, None at the end of the stream
+    pub fn read_packet(&mut self) -> Result<Option<Vec<f32>>, LoadErrorReason> {
+        let channels = self.channels as usize;
+        loop {
+            let packet = match self.packets.read_packet().map_err(LoadErrorReason::ReadOggPacketError)? {
+                Some(packet) => packet,
+                None => return Ok(None),
+            };
+            let frames = self.decoder.decode_float(&packet.data, &mut self.scratch, false).map_err(LoadErrorReason::ReadOpusError)?;
+
+            let start = self.position;
+            self.position += frames as u64;
+            let end = if packet.last_in_stream() { Some(packet.absgp_page()) } else { None };
+            let (from, to) = self.head.audio_frames(start, frames as u64, end);
+            if to <= from {
+                continue;
+            }
+
+            let gain = self.head.gain;
+            let samples = &self.scratch[(from as usize * channels)..(to as usize * channels)];
+            return Ok(Some(samples.iter().map(|&s| s * gain).collect()));
+        }
+    }
 }
 
-// fn open_stream_reader(path: &Path, packet_reader: ogg::PacketReader<File>) -> LoadResult<OggStreamReader<File>> {
-fn open_stream_reader(path: &Path, file: File) -> LoadResult<OggStreamReader<File>> {
-    OggStreamReader::new(file).map_err(|oe| LoadError { path: path.to_path_buf(), reason: LoadErrorReason::ReadOggError(oe) })
+// stream forces streaming (a single path) on or off, None streams above streaming_size
+pub fn should_stream(assets: &AssetSource, paths: &[PathBuf], stream: Option<bool>, streaming_size: u64) -> LoadResult<bool> {
+    if paths.len() != 1 {
+        return Ok(false);
+    }
+    match stream {
+        Some(stream) => Ok(stream),
+        None => Ok(assets.size(&paths[0])? > streaming_size),
+    }
 }
 
-pub fn load_combined(paths: &[PathBuf], streaming_size: u64) -> LoadResult<LoadedSound> {
+pub fn load_combined(assets: &AssetSource, paths: &[PathBuf], stream: Option<bool>, streaming_size: u64) -> LoadResult<LoadedSound> {
     if paths.len() == 1 { // if there's only one .... detect if we should stream it or not
         let path = &paths[0];
-        let size = file_size(path)?;
-        if size > streaming_size {
-            let stream = load_ogg_stream(path)?;
+        if should_stream(assets, paths, stream, streaming_size)? {
+            let stream = load_stream(assets, path)?;
             Ok(LoadedSound::Streaming(stream))
         } else {
-            let sound = load_ogg(path)?;
+            let sound = load_sound(assets, path)?;
             Ok(LoadedSound::Static(vec![sound]))
         }
     } else {
         // we just gonna load them all
         let mut loaded_sounds = Vec::new();
         for path in paths {
-            let sound = load_ogg(path)?;
+            let sound = load_sound(assets, path)?;
             loaded_sounds.push(sound);
         }
         Ok(LoadedSound::Static(loaded_sounds))
     }
 }
 
-pub fn load_ogg_stream(path: &Path) -> LoadResult<OggStreamReader<File>> {
-    let file = open_file(path)?; 
-	let srr = open_stream_reader(path, file)?;
-    Ok(srr)
+pub fn load_sound(assets: &AssetSource, path: &Path) -> LoadResult<Sound> {
+    let reader = assets.open(path)?;
+    load_sound_from(reader).map_err(|reason| LoadError { path: path.to_path_buf(), reason: reason })
 }
 
-pub fn load_ogg(path: &Path) -> LoadResult<Sound> {
-    let file = open_file(path)?;
+pub fn load_stream(assets: &AssetSource, path: &Path) -> LoadResult<SoundStream> {
+    let reader = assets.open(path)?;
+    load_stream_from(reader).map_err(|reason| LoadError { path: path.to_path_buf(), reason: reason })
+}
+
+// picks the decoder from the first bytes the reader gives
+pub fn load_sound_from(mut reader: SoundReader) -> Result<Sound, LoadErrorReason> {
+    match sound_format(&mut reader)? {
+        SoundFormat::Ogg => load_ogg(reader),
+        #[cfg(feature = "opus")]
+        SoundFormat::Opus => load_opus(reader),
+        #[cfg(not(feature = "opus"))]
+        SoundFormat::Opus => Err(LoadErrorReason::OpusDisabled),
+        SoundFormat::Wav => load_wav(reader),
+        SoundFormat::Flac => load_flac(reader),
+    }
+}
+
+pub fn load_stream_from(mut reader: SoundReader) -> Result<SoundStream, LoadErrorReason> {
+    let stream = match sound_format(&mut reader)? {
+        SoundFormat::Ogg => SoundStream::Ogg(load_ogg_stream(reader)?),
+        #[cfg(feature = "opus")]
+        SoundFormat::Opus => SoundStream::Opus(load_opus_stream(reader)?),
+        #[cfg(not(feature = "opus"))]
+        SoundFormat::Opus => return Err(LoadErrorReason::OpusDisabled),
+        SoundFormat::Wav => SoundStream::Wav(load_wav_stream(reader)?),
+        SoundFormat::Flac => {
+            let reader = load_flac_stream(reader)?;
+            let info = reader.streaminfo();
+            let loop_region = LoopRegion::from_comments(reader.tags());
+            SoundStream::Flac(reader.into_samples(), info, loop_region)
+        },
+    };
+    if stream.channels() > MAX_CHANNELS {
+        return Err(LoadErrorReason::TooManyChannels);
+    }
+    Ok(stream)
+}
+
+pub fn load_ogg_stream(reader: SoundReader) -> Result<OggStreamReader<SoundReader>, LoadErrorReason> {
+    OggStreamReader::new(reader).map_err(LoadErrorReason::ReadOggError)
+}
+
+pub fn load_ogg(reader: SoundReader) -> Result<Sound, LoadErrorReason> {
+    let mut srr = load_ogg_stream(reader)?;
 
-	let mut srr = open_stream_reader(path, file)?;
-    
-    if srr.ident_hdr.audio_channels > 2 {
-        return Err(LoadError{ path: path.to_path_buf(), reason: LoadErrorReason::TooManyChannels });
-	}
+    if srr.ident_hdr.audio_channels > MAX_CHANNELS {
+        return Err(LoadErrorReason::TooManyChannels);
+    }
 
-    let mut data : Vec<i16> = Vec::new();
-    while let Some(pck_samples) = srr.read_dec_packet_itl().map_err(|oe| LoadError {path: path.to_path_buf(), reason: LoadErrorReason::ReadOggError(oe) })? {
+    let mut data : Vec<f32> = Vec::new();
+    while let Some(pck_samples) = read_vorbis_packet(&mut srr).map_err(LoadErrorReason::ReadOggError)? {
         data.extend(pck_samples.iter());
     }
-    
+    vorbis_to_wav_order(&mut data, srr.ident_hdr.audio_channels);
+
     Ok(Sound {
         data: data,
         sample_rate: srr.ident_hdr.audio_sample_rate,
         channels: srr.ident_hdr.audio_channels,
+        loop_region: vorbis_loop_region(&srr),
+    })
+}
+
+fn vorbis_loop_region(reader: &OggStreamReader<SoundReader>) -> Option<LoopRegion> {
+    LoopRegion::from_comments(reader.comment_hdr.comment_list.iter().map(|&(ref key, ref value)| (key.as_str(), value.as_str())))
+}
+
+fn read_vorbis_packet(reader: &mut OggStreamReader<SoundReader>) -> Result<Option<Vec<f32>>, ::lewton::VorbisError> {
+    Ok(reader.read_dec_packet_generic::<InterleavedSamples<f32>>()?.map(|packet| packet.samples))
+}
+
+pub fn load_wav_stream(reader: SoundReader) -> Result<WavReader<BufReader<SoundReader>>, LoadErrorReason> {
+    WavReader::new(BufReader::new(reader)).map_err(LoadErrorReason::ReadWavError)
+}
+
+pub fn load_wav(reader: SoundReader) -> Result<Sound, LoadErrorReason> {
+    let mut reader = load_wav_stream(reader)?;
+    let spec = reader.spec();
+
+    if spec.channels > MAX_CHANNELS as u16 {
+        return Err(LoadErrorReason::TooManyChannels);
+    }
+
+    let mut data : Vec<f32> = Vec::with_capacity(reader.len() as usize);
+    let remaining = reader.len() as usize;
+    read_wav_samples(&mut reader, &mut data, remaining).map_err(LoadErrorReason::ReadWavError)?;
+
+    Ok(Sound {
+        data: data,
+        sample_rate: spec.sample_rate,
+        channels: spec.channels as u8,
+        loop_region: None,
     })
 }
+
+// reads up to samples interleaved samples, 8/16/24/32 bit integer and 32 bit float are supported
+fn read_wav_samples(reader: &mut WavReader<BufReader<SoundReader>>, data: &mut Vec<f32>, samples: usize) -> Result<usize, ::hound::Error> {
+    let spec = reader.spec();
+    let before = data.len();
+    match (spec.sample_format, spec.bits_per_sample) {
+        (SampleFormat::Float, 32) => {
+            for sample in reader.samples::<f32>().take(samples) {
+                data.push(sample?);
+            }
+        },
+        (SampleFormat::Int, bits) if bits <= 32 => {
+            let scale = int_sample_scale(bits as u32);
+            for sample in reader.samples::<i32>().take(samples) {
+                data.push((sample? as f32) * scale);
+            }
+        },
+        _ => return Err(::hound::Error::Unsupported),
+    }
+    Ok(data.len() - before)
+}
+
+pub fn load_flac_stream(reader: SoundReader) -> Result<FlacReader<BufReader<SoundReader>>, LoadErrorReason> {
+    FlacReader::new(BufReader::new(reader)).map_err(LoadErrorReason::ReadFlacError)
+}
+
+pub fn load_flac(reader: SoundReader) -> Result<Sound, LoadErrorReason> {
+    let reader = load_flac_stream(reader)?;
+    let info = reader.streaminfo();
+    let loop_region = LoopRegion::from_comments(reader.tags());
+
+    if info.channels > MAX_CHANNELS as u32 {
+        return Err(LoadErrorReason::TooManyChannels);
+    }
+
+    let total = info.samples.map(|frames| frames as usize * info.channels as usize);
+    let mut data : Vec<f32> = Vec::with_capacity(total.unwrap_or(0));
+    let mut flac_samples = reader.into_samples();
+    read_flac_samples(&mut flac_samples, info.bits_per_sample, &mut data, total.unwrap_or(::std::usize::MAX)).map_err(LoadErrorReason::ReadFlacError)?;
+
+    Ok(Sound {
+        data: data,
+        sample_rate: info.sample_rate,
+        channels: info.channels as u8,
+        loop_region: loop_region,
+    })
+}
+
+// reads up to samples interleaved samples
+fn read_flac_samples(flac_samples: &mut FlacIntoSamples<BufReader<SoundReader>>, bits_per_sample: u32, data: &mut Vec<f32>, samples: usize) -> Result<usize, ::claxon::Error> {
+    let before = data.len();
+    let scale = int_sample_scale(bits_per_sample);
+    for sample in flac_samples.take(samples) {
+        data.push((sample? as f32) * scale);
+    }
+    Ok(data.len() - before)
+}
+
+// maps a signed integer sample of bits to -1.0..1.0
+fn int_sample_scale(bits: u32) -> f32 {
+    1.0 / ((1u64 << (bits - 1)) as f32)
+}
+
+#[cfg(feature = "opus")]
+pub fn load_opus_stream(reader: SoundReader) -> Result<OpusStream, LoadErrorReason> {
+    OpusStream::new(reader)
+}
+
+#[cfg(feature = "opus")]
+pub fn load_opus(reader: SoundReader) -> Result<Sound, LoadErrorReason> {
+    let mut stream = load_opus_stream(reader)?;
+
+    let mut data : Vec<f32> = Vec::new();
+    while let Some(packet) = stream.read_packet()? {
+        data.extend(packet.iter());
+    }
+
+    Ok(Sound {
+        data: data,
+        sample_rate: OPUS_SAMPLE_RATE,
+        channels: stream.channels,
+        loop_region: stream.loop_region,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::u64;
+
+    use errors::LoadErrorReason;
+    use super::{OpusHead, LoopRegion};
+
+    // an OpusHead packet, 48kHz input, table is the channel mapping after the family byte
+    fn head(channels: u8, pre_skip: u16, gain: i16, family: u8, table: &[u8]) -> Vec<u8> {
+        let mut head = b"OpusHead".to_vec();
+        head.push(1);
+        head.push(channels);
+        head.extend(&[pre_skip as u8, (pre_skip >> 8) as u8]);
+        head.extend(&[0x80, 0xbb, 0, 0]);
+        head.extend(&[gain as u8, ((gain as u16) >> 8) as u8]);
+        head.push(family);
+        head.extend(table);
+        head
+    }
+
+    fn rejected(head: &[u8]) -> bool {
+        match OpusHead::parse(head) {
+            Err(LoadErrorReason::InvalidOpusHeader) => true,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn opus_heads_of_a_single_mono_or_stereo_stream_are_read() {
+        let parsed = OpusHead::parse(&head(2, 312, 0, 0, &[])).unwrap();
+        assert_eq!((parsed.channels, parsed.pre_skip, parsed.gain), (2, 312, 1.0));
+
+        // Q7.8 dB
+        let parsed = OpusHead::parse(&head(1, 0, 6 * 256, 0, &[])).unwrap();
+        assert!((parsed.gain - 10.0_f32.powf(6.0 / 20.0)).abs() < 1e-6);
+
+        // a mapping table of one stream straight through
+        assert!(OpusHead::parse(&head(2, 0, 0, 1, &[1, 1, 0, 1])).is_ok());
+    }
+
+    #[test]
+    fn opus_heads_without_channels_or_with_several_streams_are_rejected() {
+        assert!(rejected(&head(0, 0, 0, 0, &[])));
+        assert!(rejected(&head(6, 0, 0, 1, &[4, 2, 0, 4, 1, 2, 3, 5])));
+        // two mono streams for a stereo file
+        assert!(rejected(&head(2, 0, 0, 1, &[2, 0, 0, 1])));
+        // swapped channels, or a table that's cut short
+        assert!(rejected(&head(2, 0, 0, 1, &[1, 1, 1, 0])));
+        assert!(rejected(&head(2, 0, 0, 255, &[1, 1])));
+        assert!(rejected(&head(2, 0, 0, 0, &[])[..18]));
+        assert!(rejected(b"OggS this isn't a head"));
+    }
+
+    #[test]
+    fn opus_pre_skip_and_end_granule_are_trimmed() {
+        let parsed = OpusHead::parse(&head(1, 312, 0, 0, &[])).unwrap();
+        assert_eq!(parsed.audio_frames(0, 960, None), (312, 960));
+        assert_eq!(parsed.audio_frames(960, 960, None), (0, 960));
+        // the last page ends the audio part way through its packet
+        assert_eq!(parsed.audio_frames(1920, 960, Some(2500)), (0, 580));
+        // or before it
+        assert_eq!(parsed.audio_frames(2880, 960, Some(2500)), (0, 0));
+
+        // a packet that's all pre skip has nothing in it
+        let parsed = OpusHead::parse(&head(1, 1000, 0, 0, &[])).unwrap();
+        let (from, to) = parsed.audio_frames(0, 960, None);
+        assert!(to <= from);
+    }
+
+    #[test]
+    fn loop_comments_with_garbage_lengths_are_dropped() {
+        assert_eq!(LoopRegion::from_comments(vec![("LOOPSTART", "100"), ("looplength", " 200 ")]), Some(LoopRegion { start: 100, end: Some(300) }));
+        assert_eq!(LoopRegion::from_comments(vec![("LOOPSTART", "100")]), Some(LoopRegion { start: 100, end: None }));
+        assert_eq!(LoopRegion::from_comments(vec![("LOOPLENGTH", "100")]), None);
+        assert_eq!(LoopRegion::from_comments(vec![("LOOPSTART", "1"), ("LOOPLENGTH", "18446744073709551615")]), None);
+    }
+
+    #[test]
+    fn loop_regions_scale_without_overflowing_and_stop_at_the_end() {
+        let huge = LoopRegion { start: u64::MAX / 2, end: Some(u64::MAX) };
+        assert_eq!(huge.scaled(44100, 48000), LoopRegion { start: u64::MAX, end: Some(u64::MAX) });
+        assert_eq!(huge.scaled(0, 48000), huge);
+
+        // past the end of the sound there's nothing to loop
+        assert_eq!(LoopRegion { start: 100, end: Some(300) }.frames(200), Some((100, 200)));
+        assert_eq!(LoopRegion { start: 300, end: None }.frames(200), None);
+        assert_eq!(huge.frames(200), None);
+    }
+
+}
@@ -0,0 +1,248 @@
+use std::f64::consts::PI;
+
+use load::Sound;
+use mix::db_to_gain;
+use Gain;
+
+// integrated loudness as EBU R128 / ITU-R BS.1770 measures it, and the sample peak
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    pub integrated: f32, // LUFS, NEG_INFINITY for silence
+    pub peak: f32, // largest absolute sample, 1.0 is full scale
+}
+
+impl Loudness {
+    // the gain that brings it to target LUFS, held back so the peak doesn't go past full scale
+    pub fn normalization(&self, target: f32) -> Gain {
+        if !self.integrated.is_finite() {
+            return 1.0;
+        }
+        let gain = db_to_gain(target - self.integrated);
+        if self.peak > 0.0 {
+            gain.min(1.0 / self.peak)
+        } else {
+            gain
+        }
+    }
+}
+
+// BS.1770 gates blocks quieter than this outright
+const ABSOLUTE_GATE : f64 = -70.0;
+// and then blocks this far below the loudness of what's left
+const RELATIVE_GATE : f64 = -10.0;
+
+// a direct form 1 biquad, a0 normalised to 1
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    // the audio EQ cookbook's low pass
+    pub fn low_pass(cutoff: f64, sample_rate: f64, q: f64) -> Biquad {
+        let w0 = 2.0 * PI * cutoff / sample_rate;
+        let (sin, cos) = (w0.sin(), w0.cos());
+        let alpha = sin / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        Biquad {
+            b: [(1.0 - cos) / 2.0 / a0, (1.0 - cos) / a0, (1.0 - cos) / 2.0 / a0],
+            a: [-2.0 * cos / a0, (1.0 - alpha) / a0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[0] * self.y[0] - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+// the K weighting pre-filter (a high shelf for the head then a high pass), BS.1770's 48kHz coefficients rederived for any rate
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new(sample_rate: u32) -> KWeighting {
+        let rate = sample_rate as f64;
+
+        let f0 = 1681.974450955533;
+        let g = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / rate).tan();
+        let vh = 10.0_f64.powf(g / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad {
+            b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        };
+
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        };
+
+        KWeighting { shelf: shelf, high_pass: high_pass }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let shelved = self.shelf.process(x);
+        self.high_pass.process(shelved)
+    }
+}
+
+// per channel in WAV order, the LFE doesn't count and surrounds count for more
+fn channel_weights(channels: u8) -> Vec<f64> {
+    const S : f64 = 1.41;
+    match channels {
+        4 => vec![1.0, 1.0, S, S],
+        5 => vec![1.0, 1.0, 1.0, S, S],
+        6 => vec![1.0, 1.0, 1.0, 0.0, S, S],
+        7 => vec![1.0, 1.0, 1.0, 0.0, S, S, S],
+        8 => vec![1.0, 1.0, 1.0, 0.0, S, S, S, S],
+        _ => vec![1.0; channels as usize],
+    }
+}
+
+fn block_loudness(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / (values.len() as f64)
+}
+
+pub fn measure(sound: &Sound) -> Loudness {
+    let peak = sound.data.iter().fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+    let channels = sound.channels as usize;
+    if channels == 0 || sound.sample_rate == 0 {
+        return Loudness { integrated: ::std::f32::NEG_INFINITY, peak: peak };
+    }
+
+    let weights = channel_weights(sound.channels);
+    let mut filters : Vec<KWeighting> = (0..channels).map(|_| KWeighting::new(sound.sample_rate)).collect();
+
+    // weighted mean squares of 100ms steps, 400ms blocks overlapping by 75% are made of 4 of them
+    let step = ((sound.sample_rate / 10) as usize).max(1);
+    let mut steps : Vec<f64> = Vec::new();
+    let mut step_sum = 0.0;
+    let mut step_frames = 0;
+    let mut total = 0.0;
+    let mut frames = 0;
+    for frame in sound.data.chunks(channels) {
+        if frame.len() < channels {
+            break;
+        }
+        let mut sum = 0.0;
+        for ((sample, filter), weight) in frame.iter().zip(filters.iter_mut()).zip(weights.iter()) {
+            let y = filter.process(*sample as f64);
+            sum += weight * y * y;
+        }
+        step_sum += sum;
+        total += sum;
+        step_frames += 1;
+        frames += 1;
+        if step_frames == step {
+            steps.push(step_sum / (step as f64));
+            step_sum = 0.0;
+            step_frames = 0;
+        }
+    }
+
+    let blocks : Vec<f64> = if steps.len() >= 4 {
+        steps.windows(4).map(mean).collect()
+    } else if frames > 0 {
+        // shorter than a block, measured as one
+        vec![total / (frames as f64)]
+    } else {
+        vec![]
+    };
+
+    let above_absolute : Vec<f64> = blocks.into_iter().filter(|&z| z > 0.0 && block_loudness(z) > ABSOLUTE_GATE).collect();
+    if above_absolute.is_empty() {
+        return Loudness { integrated: ::std::f32::NEG_INFINITY, peak: peak };
+    }
+    let relative_gate = block_loudness(mean(&above_absolute)) + RELATIVE_GATE;
+    let gated : Vec<f64> = above_absolute.into_iter().filter(|&z| block_loudness(z) > relative_gate).collect();
+    let integrated = if gated.is_empty() { ::std::f32::NEG_INFINITY } else { block_loudness(mean(&gated)) as f32 };
+
+    Loudness { integrated: integrated, peak: peak }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32;
+    use std::f32::consts::PI;
+
+    use load::Sound;
+    use super::{Loudness, measure};
+
+    // interleaved, the same sine on every channel
+    fn sine(frequency: f32, amplitude: f32, seconds: f32, sample_rate: u32, channels: u8) -> Sound {
+        let frames = (seconds * sample_rate as f32) as usize;
+        let data = (0..frames).flat_map(|i| {
+            let sample = amplitude * (2.0 * PI * frequency * i as f32 / sample_rate as f32).sin();
+            vec![sample; channels as usize]
+        }).collect();
+        Sound { data: data, sample_rate: sample_rate, channels: channels, loop_region: None }
+    }
+
+    fn db(db: f32) -> f32 {
+        10.0_f32.powf(db / 20.0)
+    }
+
+    #[test]
+    fn reference_sines_measure_as_specified() {
+        // EBU Tech 3341 case 1, a stereo 997Hz sine at -23dBFS is -23 LUFS
+        let loudness = measure(&sine(997.0, db(-23.0), 3.0, 48000, 2));
+        assert!((loudness.integrated + 23.0).abs() < 0.1, "{:?}", loudness);
+        assert!((loudness.peak - db(-23.0)).abs() < 1e-3);
+
+        // a full scale mono one is -3.01 LUFS, at any rate
+        let loudness = measure(&sine(997.0, 1.0, 2.0, 44100, 1));
+        assert!((loudness.integrated + 3.01).abs() < 0.1, "{:?}", loudness);
+    }
+
+    #[test]
+    fn quiet_parts_are_gated_out() {
+        let mut sound = sine(997.0, db(-23.0), 2.0, 48000, 2);
+        sound.data.extend(sine(997.0, db(-60.0), 2.0, 48000, 2).data);
+        let loudness = measure(&sound);
+        // the plain average would be 3dB quieter
+        assert!((loudness.integrated + 23.0).abs() < 0.5, "{:?}", loudness);
+    }
+
+    #[test]
+    fn silence_is_left_as_it_is() {
+        let loudness = measure(&sine(997.0, 0.0, 1.0, 48000, 2));
+        assert_eq!(loudness, Loudness { integrated: f32::NEG_INFINITY, peak: 0.0 });
+        assert_eq!(loudness.normalization(-23.0), 1.0);
+
+        let loudness = measure(&Sound { data: vec![], sample_rate: 48000, channels: 2, loop_region: None });
+        assert_eq!(loudness.integrated, f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn normalization_doesnt_go_past_full_scale() {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+        assert!(close(Loudness { integrated: -30.0, peak: 0.1 }.normalization(-23.0), db(7.0)));
+        assert!(close(Loudness { integrated: -20.0, peak: 0.9 }.normalization(-23.0), db(-3.0)));
+        // +7dB would take a 0.5 peak past 1.0
+        assert!(close(Loudness { integrated: -30.0, peak: 0.5 }.normalization(-23.0), 2.0));
+    }
+}
@@ -0,0 +1,276 @@
+use std::io::Read;
+use std::path::PathBuf;
+
+use toml;
+use rand::Rng;
+
+use super::assets::AssetSource;
+use super::select::Selection;
+use super::errors::*;
+use {Gain, SoundName, SoundEvent, BusName, LoadResult};
+
+use aphid::HashMap;
+
+// sounds.toml at the top of the resources, sound names not in it are looked up by their file layout
+pub const MANIFEST_NAME : &'static str = "sounds";
+pub const MANIFEST_EXTENSION : &'static str = "toml";
+
+// [sounds.footstep]
+// variants = ["footsteps/grass_1", "footsteps/grass_2"]
+// gain = 0.8
+// pitch_range = [0.95, 1.05]
+// bus = "sfx"
+// selection = "avoid_last"
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Manifest {
+    #[serde(default)]
+    pub sounds: HashMap<SoundName, SoundDefinition>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SoundDefinition {
+    #[serde(default)]
+    pub variants: Vec<SoundName>, // resolved like sound names (no extension), empty looks the sound's own name up
+    #[serde(default = "unity")]
+    pub gain: Gain, // multiplies the event's gain
+    pub pitch_range: Option<(f32, f32)>, // a random multiplier of the event's pitch per play
+    pub gain_range: Option<(Gain, Gain)>, // a random multiplier of the event's gain per play
+    #[serde(default, rename = "loop")]
+    pub loop_sound: bool, // loops even if the event doesn't ask to
+    pub stream: Option<bool>, // None streams single files above the size threshold
+    pub bus: Option<BusName>, // for events that don't name one
+    #[serde(default)]
+    pub selection: Selection, // how a variant is picked
+    #[serde(default)]
+    pub weights: Vec<f32>, // for Weighted, in variants order
+    #[serde(default = "one")]
+    pub avoid_last: usize, // for AvoidLast
+    pub loudness: Option<f32>, // integrated LUFS, streams are normalized by it (static sounds are measured as they load)
+}
+
+fn unity() -> Gain {
+    1.0
+}
+
+fn one() -> usize {
+    1
+}
+
+// the part of a play's gain and pitch that isn't the event's, the manifest's randomisation
+// kept for as long as the source is bound so persistent sounds don't re-roll every render
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Variation {
+    pub gain: Gain,
+    pub pitch: f32,
+}
+
+impl Variation {
+    pub fn none() -> Variation {
+        Variation { gain: 1.0, pitch: 1.0 }
+    }
+}
+
+fn random_in<R: Rng>(rng: &mut R, range: Option<(f32, f32)>) -> f32 {
+    match range {
+        Some((low, high)) if high > low => rng.gen_range(low, high),
+        Some((low, _)) => low,
+        None => 1.0,
+    }
+}
+
+impl SoundDefinition {
+    // the parts that don't change between plays, applying it twice is the same as once
+    pub fn apply(&self, sound_event: &mut SoundEvent) {
+        sound_event.loop_sound = sound_event.loop_sound || self.loop_sound;
+        if sound_event.bus.is_none() {
+            sound_event.bus = self.bus.clone();
+        }
+    }
+
+    pub fn variation<R: Rng>(&self, rng: &mut R) -> Variation {
+        Variation {
+            gain: self.gain * random_in(rng, self.gain_range),
+            pitch: random_in(rng, self.pitch_range),
+        }
+    }
+}
+
+// an empty manifest when there isn't one
+pub fn load_manifest(assets: &AssetSource) -> LoadResult<Manifest> {
+    let path = match assets.resolve(MANIFEST_NAME, &[MANIFEST_EXTENSION.to_string()]) {
+        Some(path) => path,
+        None => return Ok(Manifest::default()),
+    };
+    let mut text = String::new();
+    assets.open(&path)?.read_to_string(&mut text).map_err(|ioe| LoadError { path: path.clone(), reason: LoadErrorReason::FileReadError(ioe) })?;
+    toml::from_str(&text).map_err(|te| LoadError { path: path, reason: LoadErrorReason::ReadManifestError(te) })
+}
+
+impl Manifest {
+    pub fn variant_paths(&self, sound_name: &str, assets: &AssetSource, extensions: &[String]) -> Option<LoadResult<Vec<PathBuf>>> {
+        let definition = match self.sounds.get(sound_name) {
+            Some(definition) if !definition.variants.is_empty() => definition,
+            _ => return None,
+        };
+        Some(definition.variants.iter().map(|variant| {
+            assets.resolve(variant, extensions).ok_or_else(|| LoadError { path: PathBuf::from(variant), reason: LoadErrorReason::FileDoesntExist })
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+
+    use toml;
+    use rand::XorShiftRng;
+
+    use {SoundEvent, LoadResult, PreloadResult, VEC3_ZERO};
+    use assets::AssetSource;
+    use backend::NullBackend;
+    use context::create_sound_context;
+    use load::SoundReader;
+    use select::Selection;
+    use errors::*;
+    use super::{Manifest, SoundDefinition};
+    use aphid::HashMap;
+
+    // files by path, swapped out as the test goes
+    struct MemoryAssets {
+        files: Mutex<HashMap<String, Vec<u8>>>,
+    }
+
+    impl MemoryAssets {
+        fn new(files: &[(&str, &str)]) -> MemoryAssets {
+            let assets = MemoryAssets { files: Mutex::new(HashMap::default()) };
+            for &(path, contents) in files {
+                assets.set(path, contents);
+            }
+            assets
+        }
+
+        fn set(&self, path: &str, contents: &str) {
+            self.files.lock().unwrap().insert(path.to_string(), contents.as_bytes().to_vec());
+        }
+    }
+
+    impl AssetSource for MemoryAssets {
+        fn resolve(&self, sound_name: &str, extensions: &[String]) -> Option<PathBuf> {
+            let files = self.files.lock().unwrap();
+            extensions.iter().map(|extension| format!("{}.{}", sound_name, extension)).find(|path| files.contains_key(path)).map(PathBuf::from)
+        }
+
+        fn variants(&self, _sound_name: &str, _extensions: &[String]) -> PreloadResult<Vec<PathBuf>> {
+            Ok(vec![])
+        }
+
+        fn open(&self, path: &Path) -> LoadResult<SoundReader> {
+            match self.files.lock().unwrap().get(path.to_str().unwrap_or("")) {
+                Some(contents) => Ok(Box::new(Cursor::new(contents.clone()))),
+                None => Err(LoadError { path: path.to_path_buf(), reason: LoadErrorReason::FileDoesntExist }),
+            }
+        }
+
+        fn size(&self, path: &Path) -> LoadResult<u64> {
+            self.files.lock().unwrap().get(path.to_str().unwrap_or("")).map(|contents| contents.len() as u64).ok_or_else(|| LoadError { path: path.to_path_buf(), reason: LoadErrorReason::FileDoesntExist })
+        }
+    }
+
+    const MANIFEST : &'static str = r#"
+        [sounds.footstep]
+        variants = ["footsteps/grass_1", "footsteps/grass_2"]
+        pitch_range = [0.9, 1.1]
+        gain_range = [0.5, 1.0]
+        gain = 0.5
+        bus = "sfx"
+        selection = "avoid_last"
+
+        [sounds.music]
+        loop = true
+        stream = true
+    "#;
+
+    fn event(name: &str) -> SoundEvent {
+        SoundEvent { name: name.to_string(), position: VEC3_ZERO, gain: 1.0, pitch: 1.0, attenuation: 1.0, loop_sound: false, reverb_send: 0.0, lowpass: None, bus: None }
+    }
+
+    #[test]
+    fn definitions_parse_with_defaults() {
+        let manifest : Manifest = toml::from_str(MANIFEST).unwrap();
+
+        let footstep = &manifest.sounds["footstep"];
+        assert_eq!(footstep.variants, vec!["footsteps/grass_1".to_string(), "footsteps/grass_2".to_string()]);
+        assert_eq!((footstep.gain, footstep.pitch_range, footstep.gain_range), (0.5, Some((0.9, 1.1)), Some((0.5, 1.0))));
+        assert_eq!((footstep.selection, footstep.avoid_last, footstep.loop_sound, footstep.stream), (Selection::AvoidLast, 1, false, None));
+
+        let music = &manifest.sounds["music"];
+        assert!(music.variants.is_empty() && music.weights.is_empty());
+        assert_eq!((music.gain, music.selection, music.loop_sound, music.stream, music.loudness), (1.0, Selection::Uniform, true, Some(true), None));
+        assert_eq!(music.bus, None);
+
+        assert!(toml::from_str::<Manifest>("[sounds.broken]\ngain = \"loud\"").is_err());
+    }
+
+    #[test]
+    fn variants_resolve_like_sound_names() {
+        let manifest : Manifest = toml::from_str(MANIFEST).unwrap();
+        let assets = MemoryAssets::new(&[("footsteps/grass_1.wav", "")]);
+        let extensions = vec!["ogg".to_string(), "wav".to_string()];
+
+        match manifest.variant_paths("footstep", &assets, &extensions) {
+            Some(Err(LoadError { path, reason: LoadErrorReason::FileDoesntExist })) => assert_eq!(path, PathBuf::from("footsteps/grass_2")),
+            other => panic!("a missing variant should be an error, not {:?}", other),
+        }
+
+        assets.set("footsteps/grass_2.ogg", "");
+        let paths = manifest.variant_paths("footstep", &assets, &extensions).unwrap().unwrap();
+        assert_eq!(paths, vec![PathBuf::from("footsteps/grass_1.wav"), PathBuf::from("footsteps/grass_2.ogg")]);
+
+        // no variants, or no entry, leave it to the file layout
+        assert!(manifest.variant_paths("music", &assets, &extensions).is_none());
+        assert!(manifest.variant_paths("door", &assets, &extensions).is_none());
+    }
+
+    #[test]
+    fn variations_stay_in_range_and_apply_fills_in_the_event() {
+        let manifest : Manifest = toml::from_str(MANIFEST).unwrap();
+        let footstep : &SoundDefinition = &manifest.sounds["footstep"];
+        let mut rng = XorShiftRng::new_unseeded();
+        for _ in 0..100 {
+            let variation = footstep.variation(&mut rng);
+            assert!(variation.gain >= 0.25 && variation.gain < 0.5);
+            assert!(variation.pitch >= 0.9 && variation.pitch < 1.1);
+        }
+        let variation = manifest.sounds["music"].variation(&mut rng);
+        assert_eq!((variation.gain, variation.pitch), (1.0, 1.0));
+
+        let mut sound_event = event("footstep");
+        footstep.apply(&mut sound_event);
+        assert_eq!(sound_event.bus, Some("sfx".to_string()));
+
+        // what the event asks for wins
+        let mut sound_event = event("music");
+        sound_event.bus = Some("ui".to_string());
+        manifest.sounds["music"].apply(&mut sound_event);
+        assert!(sound_event.loop_sound);
+        assert_eq!(sound_event.bus, Some("ui".to_string()));
+    }
+
+    #[test]
+    fn a_broken_manifest_keeps_the_last_one() {
+        let assets = Arc::new(MemoryAssets::new(&[("sounds.toml", MANIFEST)]));
+        let mut context = create_sound_context(NullBackend, assets.clone(), &["wav".to_string()], XorShiftRng::new_unseeded(), 1_000_000, 1.0, None);
+        context.reload_manifest();
+        assert!(context.manifest.sounds.contains_key("footstep"));
+
+        assets.set("sounds.toml", "[sounds.footstep\nvariants = ");
+        context.reload_manifest();
+        assert!(context.manifest.sounds.contains_key("footstep"));
+
+        assets.set("sounds.toml", "[sounds.door]");
+        context.reload_manifest();
+        assert!(context.manifest.sounds.contains_key("door") && !context.manifest.sounds.contains_key("footstep"));
+    }
+}
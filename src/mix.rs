@@ -0,0 +1,329 @@
+use {Gain, BusName};
+use effects::{LowPass, lowpass_series};
+
+use aphid::HashMap;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bus {
+    pub parent: Option<BusName>, // None hangs straight off the master gain
+    pub gain: Gain,
+}
+
+pub type Buses = HashMap<BusName, Bus>;
+
+// "while anything on trigger (or a bus under it) plays, lower target by attenuation_db over attack seconds, recover over release"
+#[derive(Clone, Debug, PartialEq)]
+pub struct DuckRule {
+    pub trigger: BusName,
+    pub target: BusName,
+    pub attenuation_db: f32,
+    pub attack: f32,
+    pub release: f32,
+}
+
+// what an event's bus and everything above it (with ducking and snapshots) does to it
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct BusMix {
+    pub gain: Gain,
+    pub reverb_send: Gain, // scales the event's reverb_send
+    pub lowpass: Option<LowPass>, // in series with the event's lowpass
+}
+
+impl BusMix {
+    pub fn unity() -> BusMix {
+        BusMix {
+            gain: 1.0,
+            reverb_send: 1.0,
+            lowpass: None,
+        }
+    }
+
+    pub fn then(&self, other: &BusMix) -> BusMix {
+        BusMix {
+            gain: self.gain * other.gain,
+            reverb_send: self.reverb_send * other.reverb_send,
+            lowpass: lowpass_series(self.lowpass, other.lowpass),
+        }
+    }
+}
+
+// bus settings layered over the buses' own, gains and sends multiply (1.0 leaves a bus alone)
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct MixSnapshot {
+    pub bus_gains: HashMap<BusName, Gain>,
+    pub bus_lowpass: HashMap<BusName, LowPass>,
+    pub bus_reverb_sends: HashMap<BusName, Gain>,
+}
+
+impl MixSnapshot {
+    // weight 0 leaves the bus alone, 1 is the whole snapshot
+    fn bus_mix(&self, bus: &BusName, weight: f32) -> BusMix {
+        BusMix {
+            gain: fade_gain(self.bus_gains.get(bus).cloned().unwrap_or(1.0), weight),
+            reverb_send: fade_gain(self.bus_reverb_sends.get(bus).cloned().unwrap_or(1.0), weight),
+            lowpass: self.bus_lowpass.get(bus).map(|lowpass| LowPass {
+                gain: fade_gain(lowpass.gain, weight),
+                gain_hf: fade_gain(lowpass.gain_hf, weight),
+            }),
+        }
+    }
+}
+
+pub fn db_to_gain(db: f32) -> Gain {
+    10.0_f32.powf(db / 20.0)
+}
+
+pub fn gain_to_db(gain: Gain) -> f32 {
+    20.0 * gain.log10()
+}
+
+// as far as fades go, so fading to (or from) 0 gain still moves evenly
+const FADE_FLOOR_DB : f32 = -96.0;
+
+// amount (0 to 1) of the way from unity to gain, interpolated in dB so fades sound even, ducking and snapshots both fade with it
+pub fn fade_gain(gain: Gain, amount: f32) -> Gain {
+    if amount >= 1.0 {
+        gain
+    } else {
+        db_to_gain(gain_to_db(gain).max(FADE_FLOOR_DB) * amount.max(0.0))
+    }
+}
+
+// seconds since the last update (0 for the first), and remembers time_ns as the last update
+fn elapsed_seconds(last_update: &mut Option<u64>, time_ns: u64) -> f32 {
+    let elapsed = last_update.map(|last| (time_ns.saturating_sub(last) as f32) / 1_000_000_000.0).unwrap_or(0.0);
+    *last_update = Some(time_ns);
+    elapsed
+}
+
+// calls f with bus and then every bus above it, a bus that isn't in buses is treated as a root
+fn for_each_bus<'a, F: FnMut(&'a BusName)>(buses: &'a Buses, bus: Option<&'a BusName>, mut f: F) {
+    let mut current = bus;
+    let mut depth = 0;
+    while let Some(name) = current {
+        // a cycle can't be deeper than the number of buses
+        if depth > buses.len() {
+            break;
+        }
+        f(name);
+        current = buses.get(name).and_then(|b| b.parent.as_ref());
+        depth += 1;
+    }
+}
+
+// the bus combined with every bus above it, modifier is applied per bus on top (ducking, snapshots)
+pub fn bus_mix<F: Fn(&BusName) -> BusMix>(buses: &Buses, bus: Option<&BusName>, modifier: F) -> BusMix {
+    let mut mix = BusMix::unity();
+    for_each_bus(buses, bus, |name| {
+        let gain = buses.get(name).map(|b| b.gain).unwrap_or(1.0);
+        mix = mix.then(&BusMix { gain: gain, .. BusMix::unity() }).then(&modifier(name));
+    });
+    mix
+}
+
+pub fn is_under(buses: &Buses, bus: Option<&BusName>, ancestor: &BusName) -> bool {
+    let mut under = false;
+    for_each_bus(buses, bus, |name| under = under || name == ancestor);
+    under
+}
+
+// moves from towards to, taking duration seconds for the whole way
+fn approach(from: f32, to: f32, duration: f32, elapsed: f32) -> f32 {
+    if duration <= 0.0 {
+        return to;
+    }
+    let step = elapsed / duration;
+    if from < to {
+        (from + step).min(to)
+    } else {
+        (from - step).max(to)
+    }
+}
+
+pub struct Ducking {
+    pub rules: Vec<DuckRule>,
+    pub amounts: Vec<f32>, // per rule, 0 is not ducked, 1 is the full attenuation
+    pub last_update: Option<u64>,
+}
+
+impl Ducking {
+    pub fn new() -> Ducking {
+        Ducking {
+            rules: Vec::new(),
+            amounts: Vec::new(),
+            last_update: None,
+        }
+    }
+
+    pub fn set_rules(&mut self, rules: Vec<DuckRule>) {
+        self.amounts = vec![0.0; rules.len()];
+        self.rules = rules;
+    }
+
+    // triggered says whether a rule's trigger bus has anything playing, true if any gain moved
+    pub fn update<F: Fn(&DuckRule) -> bool>(&mut self, time_ns: u64, triggered: F) -> bool {
+        let elapsed = elapsed_seconds(&mut self.last_update, time_ns);
+
+        let mut changed = false;
+        for (rule, amount) in self.rules.iter().zip(self.amounts.iter_mut()) {
+            let next = if triggered(rule) {
+                approach(*amount, 1.0, rule.attack, elapsed)
+            } else {
+                approach(*amount, 0.0, rule.release, elapsed)
+            };
+            if next != *amount {
+                *amount = next;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    pub fn gain(&self, bus: &BusName) -> Gain {
+        self.rules.iter().zip(self.amounts.iter())
+            .filter(|&(rule, _)| &rule.target == bus)
+            .map(|(rule, amount)| fade_gain(db_to_gain(-rule.attenuation_db), *amount))
+            .product()
+    }
+}
+
+pub struct ActiveSnapshot {
+    pub name: String,
+    pub snapshot: MixSnapshot,
+    pub weight: f32, // how far in it is
+    pub target: f32, // 1 pushed, 0 popped
+    pub transition: f32, // seconds to get from 0 to 1 (or back)
+}
+
+// snapshots layer over each other, popped ones fade out before they're dropped
+pub struct Snapshots {
+    pub stack: Vec<ActiveSnapshot>,
+    pub last_update: Option<u64>,
+}
+
+impl Snapshots {
+    pub fn new() -> Snapshots {
+        Snapshots {
+            stack: Vec::new(),
+            last_update: None,
+        }
+    }
+
+    // pushing a name that's already active crossfades, the old settings fade out over transition as the new ones (on top) fade in
+    pub fn push(&mut self, name: String, snapshot: MixSnapshot, transition: f32) {
+        self.stack.retain(|active| active.name != name || active.weight > 0.0);
+        for active in self.stack.iter_mut().filter(|active| active.name == name) {
+            active.target = 0.0;
+            active.transition = transition;
+        }
+        self.stack.push(ActiveSnapshot {
+            name: name,
+            snapshot: snapshot,
+            weight: 0.0,
+            target: 1.0,
+            transition: transition,
+        });
+    }
+
+    // popping a name that isn't active does nothing
+    pub fn pop(&mut self, name: &str, transition: f32) {
+        for active in self.stack.iter_mut().filter(|active| active.name == name && active.target > 0.0) {
+            active.target = 0.0;
+            active.transition = transition;
+        }
+    }
+
+    // true if anything moved
+    pub fn update(&mut self, time_ns: u64) -> bool {
+        let elapsed = elapsed_seconds(&mut self.last_update, time_ns);
+
+        let mut changed = false;
+        for active in self.stack.iter_mut() {
+            let next = approach(active.weight, active.target, active.transition, elapsed);
+            if next != active.weight {
+                active.weight = next;
+                changed = true;
+            }
+        }
+
+        let count = self.stack.len();
+        self.stack.retain(|active| active.target > 0.0 || active.weight > 0.0);
+        changed || self.stack.len() != count
+    }
+
+    pub fn bus_mix(&self, bus: &BusName) -> BusMix {
+        self.stack.iter().fold(BusMix::unity(), |mix, active| mix.then(&active.snapshot.bus_mix(bus, active.weight)))
+    }
+
+    // what's pushed (skipping anything on its way out), for restoring without transitions
+    pub fn pushed(&self) -> Vec<(String, MixSnapshot)> {
+        self.stack.iter().filter(|active| active.target > 0.0).map(|active| (active.name.clone(), active.snapshot.clone())).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MixSnapshot, Snapshots, fade_gain, db_to_gain};
+
+    const SECOND : u64 = 1_000_000_000;
+
+    fn music_at(gain: f32) -> MixSnapshot {
+        let mut snapshot = MixSnapshot::default();
+        snapshot.bus_gains.insert("music".to_string(), gain);
+        snapshot
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn fades_move_evenly_in_db() {
+        assert_eq!(fade_gain(0.25, 0.0), 1.0);
+        assert_eq!(fade_gain(0.25, 1.0), 0.25);
+        assert!(close(fade_gain(0.25, 0.5), 0.5));
+        assert!(close(fade_gain(db_to_gain(-12.0), 0.25), db_to_gain(-3.0)));
+        assert_eq!(fade_gain(0.0, 1.0), 0.0);
+        assert!(fade_gain(0.0, 0.5) > 0.0);
+    }
+
+    #[test]
+    fn pushing_an_active_snapshot_again_crossfades() {
+        let music = "music".to_string();
+        let mut snapshots = Snapshots::new();
+        snapshots.push("menu".to_string(), music_at(0.25), 0.0);
+        snapshots.update(0);
+        assert!(close(snapshots.bus_mix(&music).gain, 0.25));
+
+        snapshots.push("menu".to_string(), music_at(1.0), 1.0);
+        snapshots.update(SECOND / 100);
+        let just_after = snapshots.bus_mix(&music).gain;
+        assert!(just_after < 0.3, "jumped to {}", just_after);
+
+        snapshots.update(SECOND / 2);
+        let halfway = snapshots.bus_mix(&music).gain;
+        assert!(halfway > just_after && halfway < 1.0);
+
+        snapshots.update(SECOND * 2);
+        assert!(close(snapshots.bus_mix(&music).gain, 1.0));
+        assert_eq!(snapshots.stack.len(), 1);
+        assert_eq!(snapshots.pushed(), vec![("menu".to_string(), music_at(1.0))]);
+    }
+
+    #[test]
+    fn popping_fades_out_then_drops() {
+        let music = "music".to_string();
+        let mut snapshots = Snapshots::new();
+        snapshots.push("menu".to_string(), music_at(0.25), 0.0);
+        snapshots.update(0);
+
+        snapshots.pop("menu", 1.0);
+        snapshots.update(SECOND / 2);
+        assert!(close(snapshots.bus_mix(&music).gain, 0.5));
+        assert!(snapshots.pushed().is_empty());
+
+        snapshots.update(SECOND * 2);
+        assert!(snapshots.stack.is_empty());
+        assert_eq!(snapshots.bus_mix(&music).gain, 1.0);
+    }
+}
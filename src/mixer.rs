@@ -0,0 +1,579 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use alto;
+
+use {Vec3, Gain, DistanceModel, Listener, SoundProviderResult};
+use backend::{SoundBackend, SourceBackend, StaticSourceBackend, StreamingSourceBackend, SourceState};
+use effects::{LowPass, SourceEffects};
+use errors::SoundProviderError;
+
+// the mixer always renders interleaved stereo
+pub const MIXER_CHANNELS : usize = 2;
+
+// matches the OpenAL defaults for AL_REFERENCE_DISTANCE/AL_ROLLOFF_FACTOR/AL_MAX_DISTANCE
+const REFERENCE_DISTANCE : f32 = 1.0;
+const ROLLOFF_FACTOR : f32 = 1.0;
+const MAX_DISTANCE : f32 = ::std::f32::MAX;
+
+// OpenAL Soft's lowpass reference frequency, gain_hf applies above this
+const LOWPASS_REFERENCE_HZ : f32 = 5000.0;
+
+#[derive(Clone, Debug)]
+pub struct MixerBuffer {
+    pub data: Vec<f32>,
+    pub channels: u8,
+    pub sample_rate: u32,
+    pub loop_points: Option<(usize, usize)>, // frames a looping static source repeats, all of them when None
+}
+
+impl MixerBuffer {
+    pub fn frames(&self) -> usize {
+        self.data.len() / (self.channels as usize)
+    }
+
+    // linearly interpolated (left, right) at a fractional frame, mono is duplicated
+    fn sample(&self, position: f64) -> (f32, f32) {
+        let frames = self.frames();
+        let idx = position as usize;
+        let next = if idx + 1 < frames { idx + 1 } else { idx };
+        let t = (position - idx as f64) as f32;
+
+        let channels = self.channels as usize;
+        let read = |frame: usize, channel: usize| self.data[frame * channels + channel];
+
+        let left = read(idx, 0) * (1.0 - t) + read(next, 0) * t;
+        if channels == 1 {
+            (left, left)
+        } else {
+            let right = read(idx, 1) * (1.0 - t) + read(next, 1) * t;
+            (left, right)
+        }
+    }
+}
+
+struct Voice {
+    queue: VecDeque<Arc<MixerBuffer>>,
+    processed: VecDeque<Arc<MixerBuffer>>,
+    cursor: f64, // in frames of the front buffer
+    state: SourceState,
+    gain: Gain,
+    pitch: f32,
+    position: Vec3,
+    looping: bool,
+    streaming: bool,
+    lowpass: Option<LowPass>,
+    filtered: (f32, f32), // one pole lowpass history
+}
+
+impl Voice {
+    fn new(streaming: bool) -> Voice {
+        Voice {
+            queue: VecDeque::new(),
+            processed: VecDeque::new(),
+            cursor: 0.0,
+            state: SourceState::Initial,
+            gain: 1.0,
+            pitch: 1.0,
+            position: [0.0, 0.0, 0.0],
+            looping: false,
+            streaming: streaming,
+            lowpass: None,
+            filtered: (0.0, 0.0),
+        }
+    }
+
+    // gain_hf is applied as the amount of the signal above the one pole lowpass that's let through
+    fn filter(&mut self, left: f32, right: f32, sample_rate: u32) -> (f32, f32) {
+        match self.lowpass {
+            Some(lowpass) => {
+                let a = 1.0 - (-2.0 * ::std::f32::consts::PI * LOWPASS_REFERENCE_HZ / sample_rate as f32).exp();
+                let (low_left, low_right) = self.filtered;
+                let low_left = low_left + a * (left - low_left);
+                let low_right = low_right + a * (right - low_right);
+                self.filtered = (low_left, low_right);
+                (lowpass.gain * (low_left + lowpass.gain_hf * (left - low_left)),
+                 lowpass.gain * (low_right + lowpass.gain_hf * (right - low_right)))
+            },
+            None => (left, right),
+        }
+    }
+
+    fn mix(&mut self, out: &mut [f32], sample_rate: u32, gains: (f32, f32)) {
+        let (left_gain, right_gain) = gains;
+        for frame in out.chunks_mut(MIXER_CHANNELS) {
+            let (left, right, step, frames, loop_points) = match self.queue.front() {
+                Some(buffer) if buffer.frames() > 0 => {
+                    let (left, right) = buffer.sample(self.cursor);
+                    let step = (self.pitch as f64) * (buffer.sample_rate as f64) / (sample_rate as f64);
+                    (left, right, step, buffer.frames() as f64, buffer.loop_points)
+                },
+                _ => {
+                    self.state = SourceState::Stopped;
+                    return;
+                },
+            };
+
+            let (left, right) = self.filter(left, right, sample_rate);
+            frame[0] += left * left_gain;
+            frame[1] += right * right_gain;
+
+            self.cursor += step;
+            match loop_points {
+                Some((start, end)) if self.looping && !self.streaming && start < end && self.cursor >= end as f64 => {
+                    self.cursor = start as f64 + (self.cursor - end as f64) % ((end - start) as f64);
+                },
+                _ => (),
+            }
+            if self.cursor >= frames {
+                if self.streaming {
+                    self.cursor -= frames;
+                    if let Some(buffer) = self.queue.pop_front() {
+                        self.processed.push_back(buffer);
+                    }
+                } else if self.looping {
+                    self.cursor %= frames;
+                } else {
+                    self.cursor = 0.0;
+                    self.state = SourceState::Stopped;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+struct MixerState {
+    sample_rate: u32,
+    gain: Gain,
+    listener: Listener,
+    distance_model: DistanceModel,
+    voices: Vec<Voice>,
+}
+
+impl MixerState {
+    // stereo buffers aren't spatialized, same as OpenAL
+    fn voice_gains(&self, voice: &Voice) -> (f32, f32) {
+        let gain = self.gain * voice.gain;
+        let stereo = voice.queue.front().map(|b| b.channels == 2).unwrap_or(false);
+        if stereo {
+            return (gain, gain);
+        }
+
+        let listener = &self.listener;
+        let offset = sub(voice.position, listener.position);
+        let distance = length(offset);
+        let attenuated = gain * attenuation(self.distance_model, distance);
+
+        let right = normalize(cross(listener.orientation_forward, listener.orientation_up));
+        let pan = if distance > 0.0 { dot(offset, right) / distance } else { 0.0 };
+
+        // constant power
+        (attenuated * ((1.0 - pan) * 0.5).sqrt(), attenuated * ((1.0 + pan) * 0.5).sqrt())
+    }
+}
+
+fn attenuation(distance_model: DistanceModel, distance: f32) -> f32 {
+    use alto::DistanceModel::*;
+
+    let clamped = distance.max(REFERENCE_DISTANCE).min(MAX_DISTANCE);
+    match distance_model {
+        None => 1.0,
+        Inverse => (REFERENCE_DISTANCE / (REFERENCE_DISTANCE + ROLLOFF_FACTOR * (distance - REFERENCE_DISTANCE))).min(1.0),
+        InverseClamped => REFERENCE_DISTANCE / (REFERENCE_DISTANCE + ROLLOFF_FACTOR * (clamped - REFERENCE_DISTANCE)),
+        Linear => (1.0 - ROLLOFF_FACTOR * (distance - REFERENCE_DISTANCE) / (MAX_DISTANCE - REFERENCE_DISTANCE)).max(0.0).min(1.0),
+        LinearClamped => (1.0 - ROLLOFF_FACTOR * (clamped - REFERENCE_DISTANCE) / (MAX_DISTANCE - REFERENCE_DISTANCE)).max(0.0).min(1.0),
+        Exponent => (distance / REFERENCE_DISTANCE).powf(-ROLLOFF_FACTOR).min(1.0),
+        ExponentClamped => (clamped / REFERENCE_DISTANCE).powf(-ROLLOFF_FACTOR),
+    }
+}
+
+fn sub(a: Vec3, b: Vec3) -> Vec3 {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: Vec3, b: Vec3) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn length(a: Vec3) -> f32 {
+    dot(a, a).sqrt()
+}
+
+fn normalize(a: Vec3) -> Vec3 {
+    let l = length(a);
+    if l > 0.0 { [a[0] / l, a[1] / l, a[2] / l] } else { a }
+}
+
+// pure rust backend, sources are handles in to shared voice state which mix() renders from
+pub struct MixerBackend {
+    state: Rc<RefCell<MixerState>>,
+}
+
+impl MixerBackend {
+    pub fn new(sample_rate: u32) -> MixerBackend {
+        MixerBackend {
+            state: Rc::new(RefCell::new(MixerState {
+                sample_rate: sample_rate,
+                gain: 1.0,
+                listener: Listener::default(),
+                distance_model: alto::DistanceModel::None,
+                voices: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.state.borrow().sample_rate
+    }
+
+    // mixes (adds) all playing voices in to an interleaved stereo buffer, advancing them by out.len() / MIXER_CHANNELS frames
+    pub fn mix(&self, out: &mut [f32]) {
+        let mut state = self.state.borrow_mut();
+        let sample_rate = state.sample_rate;
+        let gains : Vec<(f32, f32)> = state.voices.iter().map(|v| state.voice_gains(v)).collect();
+        for (voice, gains) in state.voices.iter_mut().zip(gains) {
+            if voice.state == SourceState::Playing {
+                voice.mix(out, sample_rate, gains);
+            }
+        }
+    }
+
+    fn new_source(&self, streaming: bool) -> MixerSource {
+        let mut state = self.state.borrow_mut();
+        state.voices.push(Voice::new(streaming));
+        MixerSource {
+            voice: state.voices.len() - 1,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl SoundBackend for MixerBackend {
+    type Buffer = MixerBuffer;
+    type StaticSource = MixerSource;
+    type StreamingSource = MixerSource;
+
+    fn new_buffer(&self) -> SoundProviderResult<MixerBuffer> {
+        Ok(MixerBuffer { data: Vec::new(), channels: 1, sample_rate: self.sample_rate(), loop_points: None })
+    }
+
+    fn set_buffer_data(&self, buffer: &mut MixerBuffer, data: Vec<f32>, channels: u8, sample_rate: u32) -> SoundProviderResult<()> {
+        buffer.data = data;
+        buffer.channels = channels;
+        buffer.sample_rate = sample_rate;
+        Ok(())
+    }
+
+    fn set_buffer_loop_points(&self, buffer: &mut MixerBuffer, start: usize, end: usize) -> SoundProviderResult<bool> {
+        buffer.loop_points = Some((start, end.min(buffer.frames())));
+        Ok(true)
+    }
+
+    fn new_static_source(&self) -> SoundProviderResult<MixerSource> {
+        Ok(self.new_source(false))
+    }
+
+    fn new_streaming_source(&self) -> SoundProviderResult<MixerSource> {
+        Ok(self.new_source(true))
+    }
+
+    fn set_gain(&self, gain: Gain) -> SoundProviderResult<()> {
+        self.state.borrow_mut().gain = gain;
+        Ok(())
+    }
+
+    fn set_listener(&self, listener: &Listener) -> SoundProviderResult<()> {
+        self.state.borrow_mut().listener = *listener;
+        Ok(())
+    }
+
+    fn set_distance_model(&self, distance_model: DistanceModel) -> SoundProviderResult<()> {
+        self.state.borrow_mut().distance_model = distance_model;
+        Ok(())
+    }
+
+    // no reverb in the mixer, only the direct path filter
+    fn set_static_source_effects(&self, source: &mut MixerSource, effects: &SourceEffects) -> SoundProviderResult<()> {
+        source.set_lowpass(effects.lowpass);
+        Ok(())
+    }
+
+    fn set_streaming_source_effects(&self, source: &mut MixerSource, effects: &SourceEffects) -> SoundProviderResult<()> {
+        source.set_lowpass(effects.lowpass);
+        Ok(())
+    }
+}
+
+pub struct MixerSource {
+    voice: usize,
+    state: Rc<RefCell<MixerState>>,
+}
+
+impl MixerSource {
+    fn with_voice<T, F: FnOnce(&mut Voice) -> T>(&self, f: F) -> T {
+        let mut state = self.state.borrow_mut();
+        f(&mut state.voices[self.voice])
+    }
+
+    fn set_lowpass(&self, lowpass: Option<LowPass>) {
+        // persistent sounds are reassigned every render, only a newly filtered voice starts from a clean history
+        self.with_voice(|voice| {
+            if voice.lowpass.is_none() {
+                voice.filtered = (0.0, 0.0);
+            }
+            voice.lowpass = lowpass;
+        });
+    }
+}
+
+impl SourceBackend for MixerSource {
+    // replaying rewinds, a fresh (initial) source keeps any offset that was set before playing
+    fn play(&mut self) -> SoundProviderResult<()> {
+        self.with_voice(|voice| {
+            if voice.state == SourceState::Playing || voice.state == SourceState::Stopped {
+                voice.cursor = 0.0;
+            }
+            voice.state = SourceState::Playing;
+        });
+        Ok(())
+    }
+
+    // stopping marks every queued buffer as processed, as OpenAL does
+    fn stop(&mut self) -> SoundProviderResult<()> {
+        self.with_voice(|voice| {
+            voice.state = SourceState::Stopped;
+            voice.cursor = 0.0;
+            if voice.streaming {
+                while let Some(buffer) = voice.queue.pop_front() {
+                    voice.processed.push_back(buffer);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn state(&self) -> SoundProviderResult<SourceState> {
+        Ok(self.with_voice(|voice| voice.state))
+    }
+
+    fn set_pitch(&mut self, pitch: f32) -> SoundProviderResult<()> {
+        self.with_voice(|voice| voice.pitch = pitch);
+        Ok(())
+    }
+
+    fn set_position(&mut self, position: Vec3) -> SoundProviderResult<()> {
+        self.with_voice(|voice| voice.position = position);
+        Ok(())
+    }
+
+    fn set_gain(&mut self, gain: Gain) -> SoundProviderResult<()> {
+        self.with_voice(|voice| voice.gain = gain);
+        Ok(())
+    }
+
+    fn offset(&self) -> SoundProviderResult<f32> {
+        Ok(self.with_voice(|voice| {
+            voice.queue.front().map(|b| (voice.cursor / b.sample_rate as f64) as f32).unwrap_or(0.0)
+        }))
+    }
+
+    fn set_offset(&mut self, offset: f32) -> SoundProviderResult<()> {
+        self.with_voice(|voice| {
+            if let Some(sample_rate) = voice.queue.front().map(|b| b.sample_rate) {
+                voice.cursor = (offset as f64) * (sample_rate as f64);
+            }
+        });
+        Ok(())
+    }
+}
+
+impl StaticSourceBackend for MixerSource {
+    type Buffer = MixerBuffer;
+
+    fn set_buffer(&mut self, buffer: Arc<MixerBuffer>) -> SoundProviderResult<()> {
+        self.with_voice(|voice| {
+            voice.queue.clear();
+            voice.queue.push_back(buffer);
+            voice.cursor = 0.0;
+            voice.state = SourceState::Initial;
+        });
+        Ok(())
+    }
+
+    fn clear_buffer(&mut self) -> SoundProviderResult<()> {
+        self.with_voice(|voice| {
+            voice.queue.clear();
+            voice.cursor = 0.0;
+            voice.state = SourceState::Initial;
+        });
+        Ok(())
+    }
+
+    fn set_looping(&mut self, looping: bool) -> SoundProviderResult<()> {
+        self.with_voice(|voice| voice.looping = looping);
+        Ok(())
+    }
+}
+
+impl StreamingSourceBackend for MixerSource {
+    type Buffer = MixerBuffer;
+
+    fn buffers_queued(&self) -> SoundProviderResult<usize> {
+        Ok(self.with_voice(|voice| voice.queue.len() + voice.processed.len()))
+    }
+
+    fn buffers_processed(&self) -> SoundProviderResult<usize> {
+        Ok(self.with_voice(|voice| voice.processed.len()))
+    }
+
+    fn queue_buffer(&mut self, buffer: MixerBuffer) -> SoundProviderResult<()> {
+        self.with_voice(|voice| voice.queue.push_back(Arc::new(buffer)));
+        Ok(())
+    }
+
+    fn unqueue_buffer(&mut self) -> SoundProviderResult<MixerBuffer> {
+        match self.with_voice(|voice| voice.processed.pop_front()) {
+            Some(buffer) => Ok(Arc::try_unwrap(buffer).unwrap_or_else(|shared| (*shared).clone())),
+            None => Err(SoundProviderError::InvalidOperation("unqueue_buffer with no processed buffers")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use alto::DistanceModel;
+
+    use Listener;
+    use backend::SourceState;
+    use super::{MixerBuffer, MixerState, Voice, attenuation, MIXER_CHANNELS};
+
+    fn buffer(data: Vec<f32>, channels: u8, sample_rate: u32) -> Arc<MixerBuffer> {
+        Arc::new(MixerBuffer { data: data, channels: channels, sample_rate: sample_rate, loop_points: None })
+    }
+
+    fn playing(buffer: Arc<MixerBuffer>) -> Voice {
+        let mut voice = Voice::new(false);
+        voice.queue.push_back(buffer);
+        voice.state = SourceState::Playing;
+        voice
+    }
+
+    // the left channel of frames frames mixed at sample_rate with unity gains
+    fn mix_left(voice: &mut Voice, frames: usize, sample_rate: u32) -> Vec<f32> {
+        let mut out = vec![0.0; frames * MIXER_CHANNELS];
+        voice.mix(&mut out, sample_rate, (1.0, 1.0));
+        out.chunks(MIXER_CHANNELS).map(|frame| frame[0]).collect()
+    }
+
+    fn mixer_state(distance_model: DistanceModel) -> MixerState {
+        MixerState { sample_rate: 100, gain: 1.0, listener: Listener::default(), distance_model: distance_model, voices: Vec::new() }
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn gains_scale_each_channel_and_add_to_the_output() {
+        let mut voice = playing(buffer(vec![0.5, -0.5, 0.5, -0.5], 2, 100));
+        let mut out = vec![0.125; 2 * MIXER_CHANNELS];
+        voice.mix(&mut out, 100, (0.5, 0.25));
+        assert_eq!(out, vec![0.375, 0.0, 0.375, 0.0]);
+    }
+
+    #[test]
+    fn mono_sounds_pan_with_constant_power() {
+        let state = mixer_state(DistanceModel::None);
+        let mut voice = playing(buffer(vec![1.0], 1, 100));
+
+        // the listener faces -z with +y up, so +x is to the right
+        voice.position = [1.0, 0.0, 0.0];
+        let (left, right) = state.voice_gains(&voice);
+        assert!(close(left, 0.0) && close(right, 1.0));
+
+        voice.position = [-3.0, 0.0, 0.0];
+        let (left, right) = state.voice_gains(&voice);
+        assert!(close(left, 1.0) && close(right, 0.0));
+
+        voice.position = [0.0, 0.0, -2.0];
+        let (left, right) = state.voice_gains(&voice);
+        assert!(close(left, 0.5_f32.sqrt()) && close(right, 0.5_f32.sqrt()));
+    }
+
+    #[test]
+    fn stereo_sounds_arent_panned_or_attenuated() {
+        let state = mixer_state(DistanceModel::InverseClamped);
+        let mut voice = playing(buffer(vec![1.0, 1.0], 2, 100));
+        voice.position = [4.0, 0.0, 0.0];
+        voice.gain = 0.5;
+        assert_eq!(state.voice_gains(&voice), (0.5, 0.5));
+    }
+
+    #[test]
+    fn distance_attenuates_mono_sounds() {
+        assert_eq!(attenuation(DistanceModel::None, 10.0), 1.0);
+        assert!(close(attenuation(DistanceModel::InverseClamped, 4.0), 0.25));
+        assert!(close(attenuation(DistanceModel::InverseClamped, 0.5), 1.0)); // clamped to the reference distance
+        assert!(close(attenuation(DistanceModel::ExponentClamped, 2.0), 0.5));
+
+        let state = mixer_state(DistanceModel::InverseClamped);
+        let mut voice = playing(buffer(vec![1.0], 1, 100));
+        voice.position = [0.0, 0.0, -2.0];
+        let (left, right) = state.voice_gains(&voice);
+        assert!(close(left, 0.5 * 0.5_f32.sqrt()) && close(right, 0.5 * 0.5_f32.sqrt()));
+    }
+
+    #[test]
+    fn pitch_steps_through_the_buffer() {
+        let ramp = vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0];
+
+        let mut voice = playing(buffer(ramp.clone(), 1, 100));
+        voice.pitch = 2.0;
+        assert_eq!(mix_left(&mut voice, 6, 100), vec![0.0, 2.0, 4.0, 6.0, 0.0, 0.0]);
+        assert_eq!(voice.state, SourceState::Stopped);
+
+        // in between frames are interpolated
+        let mut voice = playing(buffer(ramp.clone(), 1, 100));
+        voice.pitch = 0.5;
+        assert_eq!(mix_left(&mut voice, 4, 100), vec![0.0, 0.5, 1.0, 1.5]);
+
+        // as is a buffer at another rate
+        let mut voice = playing(buffer(ramp, 1, 200));
+        assert_eq!(mix_left(&mut voice, 3, 100), vec![0.0, 2.0, 4.0]);
+    }
+
+    #[test]
+    fn looping_wraps_to_the_start() {
+        let mut voice = playing(buffer(vec![0.0, 1.0, 2.0, 3.0], 1, 100));
+        voice.looping = true;
+        assert_eq!(mix_left(&mut voice, 10, 100), vec![0.0, 1.0, 2.0, 3.0, 0.0, 1.0, 2.0, 3.0, 0.0, 1.0]);
+        assert_eq!(voice.state, SourceState::Playing);
+
+        let mut voice = playing(buffer(vec![0.0, 1.0, 2.0, 3.0], 1, 100));
+        assert_eq!(mix_left(&mut voice, 6, 100), vec![0.0, 1.0, 2.0, 3.0, 0.0, 0.0]);
+        assert_eq!(voice.state, SourceState::Stopped);
+    }
+
+    #[test]
+    fn loop_points_play_the_intro_once_then_the_region() {
+        let looped = Arc::new(MixerBuffer { data: vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0], channels: 1, sample_rate: 100, loop_points: Some((2, 5)) });
+        let mut voice = playing(looped.clone());
+        voice.looping = true;
+        assert_eq!(mix_left(&mut voice, 9, 100), vec![0.0, 1.0, 2.0, 3.0, 4.0, 2.0, 3.0, 4.0, 2.0]);
+        assert_eq!(voice.state, SourceState::Playing);
+
+        // not looping plays it all through, past the region
+        let mut voice = playing(looped);
+        assert_eq!(mix_left(&mut voice, 7, 100), vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 0.0]);
+        assert_eq!(voice.state, SourceState::Stopped);
+    }
+}
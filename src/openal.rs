@@ -0,0 +1,401 @@
+use alto;
+use alto::{Alto, Context, Buffer, StaticSource, StreamingSource, SourceTrait, DeviceTrait};
+use alto::{Mono, Stereo, McQuad, Mc51Chn, Mc61Chn, Mc71Chn};
+use alto::efx::{AuxEffectSlot, ReverbEffect, LowpassFilter};
+
+use std::sync::Arc;
+use std::cell::{RefCell, RefMut};
+use std::ffi::{CStr, CString};
+
+use {Vec3, Gain, DistanceModel, Listener, SoundProviderResult};
+use backend::{SoundBackend, SourceBackend, StaticSourceBackend, StreamingSourceBackend, SourceState, ContextAttributes, HrtfStatus};
+use errors::SoundProviderError;
+use effects::{ReverbParams, SourceEffects};
+
+pub struct OpenALBackend<'d> {
+    pub context: &'d Context<'d>,
+    pub attributes: ContextAttributes, // requested at creation, reused when resetting the device for HRTF changes
+    efx: RefCell<Option<OpenALEfx<'d>>>, // created on first use
+}
+
+// one reverb slot shared by every source (on send 0), the filters are scratch space as sources copy their parameters
+struct OpenALEfx<'d> {
+    slot: AuxEffectSlot<'d, 'd>,
+    reverb: ReverbEffect<'d, 'd>,
+    send_filter: LowpassFilter<'d, 'd>,
+    direct_filter: LowpassFilter<'d, 'd>,
+}
+
+impl<'d> OpenALBackend<'d> {
+    pub fn new(context: &'d Context<'d>, attributes: ContextAttributes) -> OpenALBackend<'d> {
+        OpenALBackend {
+            context: context,
+            attributes: attributes,
+            efx: RefCell::new(None),
+        }
+    }
+
+    // None when the device has no EFX support
+    fn efx(&self) -> SoundProviderResult<Option<RefMut<OpenALEfx<'d>>>> {
+        if !self.context.device().is_extension_present(alto::ext::Alc::Efx) {
+            return Ok(None);
+        }
+        let mut efx = self.efx.borrow_mut();
+        if efx.is_none() {
+            *efx = Some(OpenALEfx {
+                slot: self.context.new_aux_effect_slot()?,
+                reverb: self.context.new_effect::<ReverbEffect>()?,
+                send_filter: self.context.new_filter::<LowpassFilter>()?,
+                direct_filter: self.context.new_filter::<LowpassFilter>()?,
+            });
+        }
+        Ok(Some(RefMut::map(efx, |efx| efx.as_mut().unwrap())))
+    }
+
+    fn set_source_effects<S: SourceTrait<'d, 'd>>(&self, source: &mut S, effects: &SourceEffects) -> SoundProviderResult<()> {
+        if let Some(mut efx) = self.efx()? {
+            let efx = &mut *efx;
+            efx.send_filter.set_gain(effects.reverb_send)?;
+            efx.send_filter.set_gainhf(1.0)?;
+            source.set_aux_send_filter(0, &mut efx.slot, &efx.send_filter)?;
+            match effects.lowpass {
+                Some(lowpass) => {
+                    efx.direct_filter.set_gain(lowpass.gain)?;
+                    efx.direct_filter.set_gainhf(lowpass.gain_hf)?;
+                    source.set_direct_filter(&efx.direct_filter)?;
+                },
+                None => source.clear_direct_filter()?,
+            }
+        }
+        Ok(())
+    }
+
+    fn has_hrtf(&self) -> bool {
+        self.context.device().is_extension_present(alto::ext::Alc::SoftHrtf)
+    }
+}
+
+pub fn output_device_names(alto: &Alto) -> Vec<String> {
+    alto.enumerate_outputs().iter().map(|name| device_name(name)).collect()
+}
+
+pub fn device_name(specifier: &CStr) -> String {
+    specifier.to_string_lossy().into_owned()
+}
+
+// None (or an unavailable device) gets the default output
+pub fn open_output<'a>(alto: &'a Alto, name: Option<&str>) -> SoundProviderResult<alto::Device<'a>> {
+    if let Some(name) = name {
+        match CString::new(name) {
+            Ok(specifier) => match alto.open(Some(&specifier)) {
+                Ok(dev) => return Ok(dev),
+                Err(err) => println!("Sound worker couldn't open output device {:?} err -> {:?}, falling back to default", name, err),
+            },
+            Err(_) => println!("Sound worker received invalid output device name {:?}, falling back to default", name),
+        }
+    }
+    Ok(alto.open(None)?)
+}
+
+pub fn opened_device_name<'a>(dev: &alto::Device<'a>) -> String {
+    dev.specifier().map(device_name).unwrap_or_else(|| String::from("default"))
+}
+
+pub fn context_attrs(attributes: &ContextAttributes) -> alto::ContextAttrs {
+    alto::ContextAttrs {
+        frequency: attributes.frequency.map(|f| f as i32),
+        refresh: attributes.refresh.map(|r| r as i32),
+        mono_sources: attributes.mono_sources.map(|s| s as i32),
+        stereo_sources: attributes.stereo_sources.map(|s| s as i32),
+        soft_hrtf: attributes.hrtf,
+        ..Default::default()
+    }
+}
+
+// reads back the attributes of the device's current context, these can differ from what was requested
+pub fn granted_attributes<'a>(alto: &Alto, dev: &alto::Device<'a>) -> ContextAttributes {
+    use alto::sys;
+
+    let api = alto.raw_api();
+    let raw = dev.as_raw();
+
+    let mut size : sys::ALCint = 0;
+    unsafe { api.alcGetIntegerv(raw, sys::ALC_ATTRIBUTES_SIZE, 1, &mut size); }
+
+    let mut attrs : Vec<sys::ALCint> = vec![0; size.max(0) as usize];
+    if size > 0 {
+        unsafe { api.alcGetIntegerv(raw, sys::ALC_ALL_ATTRIBUTES, size, attrs.as_mut_ptr()); }
+    }
+
+    // zero terminated key, value pairs
+    let mut granted = ContextAttributes::default();
+    for pair in attrs.chunks(2) {
+        if pair.len() < 2 || pair[0] == 0 {
+            break;
+        }
+        match pair[0] {
+            sys::ALC_FREQUENCY => granted.frequency = Some(pair[1] as u32),
+            sys::ALC_REFRESH => granted.refresh = Some(pair[1] as u32),
+            sys::ALC_MONO_SOURCES => granted.mono_sources = Some(pair[1] as u32),
+            sys::ALC_STEREO_SOURCES => granted.stereo_sources = Some(pair[1] as u32),
+            sys::ALC_HRTF_SOFT => granted.hrtf = Some(pair[1] != 0),
+            _ => (),
+        }
+    }
+    granted
+}
+
+// picks the frame format for the channel count, these are in WAV order as the channels module expects
+macro_rules! set_buffer_frames {
+    ($buffer:expr, $data:expr, $channels:expr, $sample_rate:expr, $sample:ty) => {
+        match $channels {
+            1 => $buffer.set_data::<Mono<$sample>, _>($data, $sample_rate)?,
+            2 => $buffer.set_data::<Stereo<$sample>, _>($data, $sample_rate)?,
+            4 => $buffer.set_data::<McQuad<$sample>, _>($data, $sample_rate)?,
+            6 => $buffer.set_data::<Mc51Chn<$sample>, _>($data, $sample_rate)?,
+            7 => $buffer.set_data::<Mc61Chn<$sample>, _>($data, $sample_rate)?,
+            8 => $buffer.set_data::<Mc71Chn<$sample>, _>($data, $sample_rate)?,
+            _ => return Err(SoundProviderError::InvalidOperation("unsupported channel count")),
+        }
+    }
+}
+
+impl<'d> SoundBackend for OpenALBackend<'d> {
+    type Buffer = Buffer<'d, 'd>;
+    type StaticSource = StaticSource<'d, 'd>;
+    type StreamingSource = StreamingSource<'d, 'd>;
+
+    fn new_buffer(&self) -> SoundProviderResult<Buffer<'d, 'd>> {
+        Ok(self.context.new_buffer()?)
+    }
+
+    // AL_EXT_FLOAT32 takes the samples as they are, without it they're converted to 16 bit
+    fn set_buffer_data(&self, buffer: &mut Buffer<'d, 'd>, data: Vec<f32>, channels: u8, sample_rate: u32) -> SoundProviderResult<()> {
+        if self.context.is_extension_present(alto::ext::Al::Float32) {
+            set_buffer_frames!(buffer, data, channels, sample_rate as i32, f32);
+        } else {
+            let data : Vec<i16> = data.iter().map(|sample| (sample.max(-1.0).min(1.0) * 32767.0) as i16).collect();
+            set_buffer_frames!(buffer, data, channels, sample_rate as i32, i16);
+        }
+        Ok(())
+    }
+
+    // AL_SOFT_loop_points, the buffer can't be attached to a source when they're set
+    fn set_buffer_loop_points(&self, buffer: &mut Buffer<'d, 'd>, start: usize, end: usize) -> SoundProviderResult<bool> {
+        if !self.context.is_extension_present(alto::ext::Al::SoftLoopPoints) {
+            return Ok(false);
+        }
+        buffer.set_soft_loop_points([start as i32, end as i32])?;
+        Ok(true)
+    }
+
+    // quad, 5.1, 6.1 and 7.1 need AL_EXT_MCFORMATS
+    fn supports_channels(&self, channels: u8) -> bool {
+        match channels {
+            1 | 2 => true,
+            4 | 6 | 7 | 8 => self.context.is_extension_present(alto::ext::Al::McFormats),
+            _ => false,
+        }
+    }
+
+    fn new_static_source(&self) -> SoundProviderResult<StaticSource<'d, 'd>> {
+        Ok(self.context.new_static_source()?)
+    }
+
+    fn new_streaming_source(&self) -> SoundProviderResult<StreamingSource<'d, 'd>> {
+        Ok(self.context.new_streaming_source()?)
+    }
+
+    fn set_gain(&self, gain: Gain) -> SoundProviderResult<()> {
+        self.context.set_gain(gain)?;
+        Ok(())
+    }
+
+    fn set_listener(&self, listener: &Listener) -> SoundProviderResult<()> {
+        self.context.set_position(listener.position)?;
+        self.context.set_velocity(listener.velocity)?;
+        self.context.set_orientation::<[f32; 3]>((listener.orientation_forward.into(), listener.orientation_up.into()))?;
+        Ok(())
+    }
+
+    fn set_distance_model(&self, distance_model: DistanceModel) -> SoundProviderResult<()> {
+        self.context.set_distance_model(distance_model)?;
+        Ok(())
+    }
+
+    // without ALC_EXT_disconnect (e.g. Apple's OpenAL) there's no telling, the device is taken to be there
+    fn connected(&self) -> SoundProviderResult<bool> {
+        if !self.context.device().is_extension_present(alto::ext::Alc::Disconnect) {
+            return Ok(true);
+        }
+        Ok(self.context.device().connected()?)
+    }
+
+    fn hrtf_profiles(&self) -> SoundProviderResult<Vec<String>> {
+        if !self.has_hrtf() {
+            return Ok(Vec::new());
+        }
+        Ok(self.context.device().enumerate_soft_hrtfs()?.iter().map(|name| device_name(name)).collect())
+    }
+
+    fn hrtf_status(&self) -> SoundProviderResult<HrtfStatus> {
+        if !self.has_hrtf() {
+            return Ok(HrtfStatus::Unsupported);
+        }
+        let status = match self.context.device().soft_hrtf_status()? {
+            alto::SoftHrtfStatus::Disabled => HrtfStatus::Disabled,
+            alto::SoftHrtfStatus::Enabled => HrtfStatus::Enabled,
+            alto::SoftHrtfStatus::Denied => HrtfStatus::Denied,
+            alto::SoftHrtfStatus::Required => HrtfStatus::Required,
+            alto::SoftHrtfStatus::HeadphonesDetected => HrtfStatus::HeadphonesDetected,
+            alto::SoftHrtfStatus::UnsupportedFormat => HrtfStatus::UnsupportedFormat,
+            _ => HrtfStatus::Unsupported,
+        };
+        Ok(status)
+    }
+
+    fn set_reverb(&self, reverb: Option<&ReverbParams>) -> SoundProviderResult<()> {
+        if let Some(mut efx) = self.efx()? {
+            let efx = &mut *efx;
+            match reverb {
+                Some(params) => {
+                    efx.reverb.set_density(params.density)?;
+                    efx.reverb.set_diffusion(params.diffusion)?;
+                    efx.reverb.set_gain(params.gain)?;
+                    efx.reverb.set_gainhf(params.gain_hf)?;
+                    efx.reverb.set_decay_time(params.decay_time)?;
+                    efx.reverb.set_decay_hfratio(params.decay_hf_ratio)?;
+                    efx.reverb.set_reflections_gain(params.reflections_gain)?;
+                    efx.reverb.set_reflections_delay(params.reflections_delay)?;
+                    efx.reverb.set_late_reverb_gain(params.late_reverb_gain)?;
+                    efx.reverb.set_late_reverb_delay(params.late_reverb_delay)?;
+                    efx.reverb.set_air_absorption_gainhf(params.air_absorption_gain_hf)?;
+                    efx.reverb.set_room_rolloff_factor(params.room_rolloff_factor)?;
+                    efx.reverb.set_decay_hflimit(params.decay_hf_limit)?;
+                    // the slot takes a copy, so it has to be set again after every change
+                    efx.slot.set_effect(&efx.reverb)?;
+                },
+                None => efx.slot.clear_effect()?,
+            }
+        }
+        Ok(())
+    }
+
+    fn set_static_source_effects(&self, source: &mut StaticSource<'d, 'd>, effects: &SourceEffects) -> SoundProviderResult<()> {
+        self.set_source_effects(source, effects)
+    }
+
+    fn set_streaming_source_effects(&self, source: &mut StreamingSource<'d, 'd>, effects: &SourceEffects) -> SoundProviderResult<()> {
+        self.set_source_effects(source, effects)
+    }
+
+    // ALC_SOFT_HRTF applies on a device reset, which keeps the context and its sources
+    fn set_hrtf(&self, enabled: bool, profile: Option<&str>) -> SoundProviderResult<bool> {
+        if !self.has_hrtf() {
+            return Ok(profile.is_none());
+        }
+        let mut attrs = context_attrs(&self.attributes);
+        attrs.soft_hrtf = Some(enabled);
+        if let Some(profile) = profile {
+            match self.hrtf_profiles()?.iter().position(|p| p == profile) {
+                Some(idx) => attrs.soft_hrtf_id = Some(idx as i32),
+                None => return Ok(false), // rather than quietly getting the default one
+            }
+        }
+        self.context.device().soft_reset(Some(attrs))?;
+        Ok(true)
+    }
+}
+
+// both alto source types share SourceTrait, the calls are fully qualified so they don't resolve back to SourceBackend
+macro_rules! source_backend_impl {
+    ($source:ident) => {
+        impl<'d> SourceBackend for $source<'d, 'd> {
+            fn play(&mut self) -> SoundProviderResult<()> {
+                SourceTrait::play(self)?;
+                Ok(())
+            }
+
+            fn stop(&mut self) -> SoundProviderResult<()> {
+                SourceTrait::stop(self)?;
+                Ok(())
+            }
+
+            fn state(&self) -> SoundProviderResult<SourceState> {
+                let state = match SourceTrait::state(self)? {
+                    alto::SourceState::Initial => SourceState::Initial,
+                    alto::SourceState::Playing => SourceState::Playing,
+                    alto::SourceState::Paused => SourceState::Paused,
+                    alto::SourceState::Stopped => SourceState::Stopped,
+                };
+                Ok(state)
+            }
+
+            fn set_pitch(&mut self, pitch: f32) -> SoundProviderResult<()> {
+                SourceTrait::set_pitch(self, pitch)?;
+                Ok(())
+            }
+
+            fn set_position(&mut self, position: Vec3) -> SoundProviderResult<()> {
+                SourceTrait::set_position(self, position)?;
+                Ok(())
+            }
+
+            fn set_gain(&mut self, gain: Gain) -> SoundProviderResult<()> {
+                SourceTrait::set_gain(self, gain)?;
+                Ok(())
+            }
+
+            fn offset(&self) -> SoundProviderResult<f32> {
+                Ok(SourceTrait::sec_offset(self)? as f32)
+            }
+
+            fn set_offset(&mut self, offset: f32) -> SoundProviderResult<()> {
+                SourceTrait::set_sec_offset(self, offset as f64)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+source_backend_impl!(StaticSource);
+source_backend_impl!(StreamingSource);
+
+impl<'d> StaticSourceBackend for StaticSource<'d, 'd> {
+    type Buffer = Buffer<'d, 'd>;
+
+    fn set_buffer(&mut self, buffer: Arc<Buffer<'d, 'd>>) -> SoundProviderResult<()> {
+        StaticSource::set_buffer(self, buffer)?;
+        Ok(())
+    }
+
+    fn clear_buffer(&mut self) -> SoundProviderResult<()> {
+        StaticSource::clear_buffer(self)?;
+        Ok(())
+    }
+
+    fn set_looping(&mut self, looping: bool) -> SoundProviderResult<()> {
+        StaticSource::set_looping(self, looping)?;
+        Ok(())
+    }
+}
+
+impl<'d> StreamingSourceBackend for StreamingSource<'d, 'd> {
+    type Buffer = Buffer<'d, 'd>;
+
+    fn buffers_queued(&self) -> SoundProviderResult<usize> {
+        Ok(StreamingSource::buffers_queued(self)? as usize)
+    }
+
+    fn buffers_processed(&self) -> SoundProviderResult<usize> {
+        Ok(StreamingSource::buffers_processed(self)? as usize)
+    }
+
+    fn queue_buffer(&mut self, buffer: Buffer<'d, 'd>) -> SoundProviderResult<()> {
+        StreamingSource::queue_buffer(self, buffer).map_err(|(error, _)| SoundProviderError::Alto(error))
+    }
+
+    fn unqueue_buffer(&mut self) -> SoundProviderResult<Buffer<'d, 'd>> {
+        Ok(StreamingSource::unqueue_buffer(self)?)
+    }
+}
@@ -0,0 +1,96 @@
+use std::path::Path;
+use std::cmp::Ordering;
+
+use hound;
+
+use super::engine::{SoundEngineUpdate, SoundEngine};
+use super::context::create_sound_context;
+use super::mixer::{MixerBackend, MIXER_CHANNELS};
+use super::worker::SoundWorkerConfig;
+use super::errors::*;
+use {SoundProviderResult, RenderResult};
+
+// an update and the time (in seconds from the start of the render) it's applied at
+pub type ScriptedUpdate = (f64, SoundEngineUpdate);
+
+// how much is mixed between stream refills when updates are further apart than this
+const RENDER_STEP_SECONDS : f64 = 0.01;
+
+// deterministically renders a script of updates through the software mixer, no clock or device involved
+// returns interleaved stereo frames at config.sample_rate() covering duration seconds (or up to a scripted Stop)
+// script times have to be finite and not negative
+pub fn render(config: SoundWorkerConfig, mut script: Vec<ScriptedUpdate>, duration: f64) -> SoundProviderResult<Vec<f32>> {
+    if script.iter().any(|&(time, _)| !time.is_finite() || time < 0.0) {
+        return Err(SoundProviderError::InvalidOperation("scripted update times must be finite and not negative"));
+    }
+
+    let sample_rate = config.sample_rate();
+    let mut cb = create_sound_context(MixerBackend::new(sample_rate), config.asset_source(), &config.extensions, config.rng, config.streaming_threshold, config.streaming_buffer_duration, config.resample_rate);
+    cb.loudness_target = config.loudness_target;
+    cb.create(config.static_sources, config.streaming_sources)?;
+
+    let mut engine = SoundEngine::new();
+
+    // all finite, so they all compare
+    script.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+
+    let total_frames = (duration * sample_rate as f64) as usize;
+    let mut frames : Vec<f32> = Vec::with_capacity(total_frames * MIXER_CHANNELS);
+    let mut mixed_frames : usize = 0;
+    let mut script = script.into_iter().peekable();
+
+    while mixed_frames < total_frames {
+        // apply everything that's due
+        while script.peek().map(|&(time, _)| ((time * sample_rate as f64) as usize) <= mixed_frames).unwrap_or(false) {
+            let (time, update) = script.next().unwrap();
+            let time_ns = (time * 1_000_000_000.0) as u64;
+            if !engine.process_at(&mut cb, update, time_ns)? {
+                return Ok(frames);
+            }
+        }
+
+        let time_ns = (mixed_frames as u64) * 1_000_000_000 / (sample_rate as u64);
+        engine.tick(&mut cb, time_ns)?;
+
+        match cb.ensure_buffers_queued() {
+            Ok(_) => (),
+            Err(PreloadError::LoadError(le)) => println!("Sound render received load error while ensuring buffers are queued {:?}", le),
+            Err(PreloadError::SoundProviderError(sp)) => return Err(sp),
+        }
+
+        let mut step = ((RENDER_STEP_SECONDS * sample_rate as f64) as usize).max(1).min(total_frames - mixed_frames);
+        if let Some(&(time, _)) = script.peek() {
+            let due = (time * sample_rate as f64) as usize;
+            step = step.min(due - mixed_frames);
+        }
+
+        let start = frames.len();
+        frames.resize(start + step * MIXER_CHANNELS, 0.0);
+        cb.backend.mix(&mut frames[start..]);
+        mixed_frames += step;
+    }
+
+    Ok(frames)
+}
+
+pub fn write_wav(path: &Path, sample_rate: u32, frames: &[f32]) -> Result<(), hound::Error> {
+    let spec = hound::WavSpec {
+        channels: MIXER_CHANNELS as u16,
+        sample_rate: sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for sample in frames {
+        let clamped = sample.max(-1.0).min(1.0);
+        writer.write_sample((clamped * 32767.0) as i16)?;
+    }
+    writer.finalize()
+}
+
+pub fn render_to_wav(path: &Path, config: SoundWorkerConfig, script: Vec<ScriptedUpdate>, duration: f64) -> RenderResult<()> {
+    let sample_rate = config.sample_rate();
+    let frames = render(config, script, duration)?;
+    write_wav(path, sample_rate, &frames)?;
+    Ok(())
+}
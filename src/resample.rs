@@ -0,0 +1,157 @@
+use load::Sound;
+use loudness::Biquad;
+
+// linear interpolation, the position, last frame and filter state carry over between chunks so streams can be converted piecewise
+// going down in rate the input is low passed first, so what's above the new nyquist doesn't alias
+pub struct Resampler {
+    pub from: u32,
+    pub to: u32,
+    channels: usize,
+    position: f64, // of the next output frame in input frames, relative to the start of the next chunk
+    previous: Vec<f32>, // last frame of the previous chunk, empty before the first
+    anti_alias: Vec<[Biquad; 2]>, // per channel, empty going up in rate
+}
+
+// the anti alias filter's cutoff as a fraction of the output rate, a little under its nyquist
+const ANTI_ALIAS_CUTOFF : f64 = 0.45;
+// the Qs of a 4th order butterworth as two biquads
+const BUTTERWORTH_Q : [f64; 2] = [0.5411961, 1.3065630];
+
+impl Resampler {
+    pub fn new(from: u32, to: u32, channels: u8) -> Resampler {
+        Resampler {
+            from: from,
+            to: to,
+            channels: channels as usize,
+            position: 0.0,
+            previous: Vec::new(),
+            anti_alias: if to < from {
+                let cutoff = ANTI_ALIAS_CUTOFF * to as f64;
+                (0..channels).map(|_| [Biquad::low_pass(cutoff, from as f64, BUTTERWORTH_Q[0]), Biquad::low_pass(cutoff, from as f64, BUTTERWORTH_Q[1])]).collect()
+            } else {
+                Vec::new()
+            },
+        }
+    }
+
+    // interleaved in, interleaved out
+    pub fn process(&mut self, input: &[f32]) -> Vec<f32> {
+        let channels = self.channels;
+        let frames = input.len() / channels;
+        if frames == 0 {
+            return Vec::new();
+        }
+
+        let filtered : Vec<f32>;
+        let input = if self.anti_alias.is_empty() {
+            input
+        } else {
+            let anti_alias = &mut self.anti_alias;
+            filtered = input[..frames * channels].iter().enumerate().map(|(i, &sample)| {
+                let filters = &mut anti_alias[i % channels];
+                filters[1].process(filters[0].process(sample as f64)) as f32
+            }).collect();
+            &filtered[..]
+        };
+
+        let step = (self.from as f64) / (self.to as f64);
+        let mut output = Vec::with_capacity(((frames as f64) / step) as usize * channels + channels);
+        {
+            let previous = &self.previous;
+            // frame -1 is the last one of the previous chunk
+            let sample = |frame: isize, channel: usize| -> f32 {
+                if frame < 0 {
+                    previous[channel]
+                } else {
+                    input[(frame as usize) * channels + channel]
+                }
+            };
+
+            while self.position <= (frames - 1) as f64 {
+                let idx = self.position.floor() as isize;
+                let t = (self.position - idx as f64) as f32;
+                let next = (idx + 1).min(frames as isize - 1);
+                for channel in 0..channels {
+                    let a = sample(idx, channel);
+                    let b = sample(next, channel);
+                    output.push(a + (b - a) * t);
+                }
+                self.position += step;
+            }
+        }
+
+        self.position -= frames as f64;
+        self.previous = input[(frames - 1) * channels..frames * channels].to_vec();
+        output
+    }
+}
+
+pub fn resample(sound: Sound, sample_rate: u32) -> Sound {
+    if sound.sample_rate == sample_rate || sound.channels == 0 {
+        return sound;
+    }
+    let mut resampler = Resampler::new(sound.sample_rate, sample_rate, sound.channels);
+    Sound {
+        data: resampler.process(&sound.data),
+        sample_rate: sample_rate,
+        channels: sound.channels,
+        loop_region: sound.loop_region.map(|region| region.scaled(sound.sample_rate, sample_rate)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use load::{Sound, LoopRegion};
+    use super::{Resampler, resample};
+
+    fn sine(frequency: f32, sample_rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames).map(|i| (2.0 * PI * frequency * i as f32 / sample_rate as f32).sin()).collect()
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn chunks_carry_over_into_the_next() {
+        let mut resampler = Resampler::new(1, 2, 1);
+        assert_eq!(resampler.process(&[0.0, 1.0, 2.0, 3.0]), vec![0.0, 0.5, 1.0, 1.5, 2.0, 2.5, 3.0]);
+
+        // the frame between chunks is interpolated from the last one of the previous
+        let mut resampler = Resampler::new(1, 2, 1);
+        let mut chunked = resampler.process(&[0.0, 1.0]);
+        chunked.extend(resampler.process(&[2.0, 3.0]));
+        assert_eq!(chunked, vec![0.0, 0.5, 1.0, 1.5, 2.0, 2.5, 3.0]);
+
+        // filtered too, stereo and in uneven chunks
+        let input : Vec<f32> = sine(440.0, 44100, 300).iter().flat_map(|&s| vec![s, -s]).collect();
+        let whole = Resampler::new(44100, 22050, 2).process(&input);
+        let mut resampler = Resampler::new(44100, 22050, 2);
+        let chunked : Vec<f32> = input.chunks(2 * 37).flat_map(|chunk| resampler.process(chunk)).collect();
+        assert_eq!(whole.len(), chunked.len());
+        assert!(whole.iter().zip(chunked.iter()).all(|(a, b)| (a - b).abs() < 1e-6));
+    }
+
+    #[test]
+    fn downsampling_filters_out_what_would_alias() {
+        // 18kHz is past 22.05kHz's nyquist, it'd fold back to 4.05kHz
+        let high = Resampler::new(48000, 22050, 1).process(&sine(18000.0, 48000, 48000));
+        assert!(rms(&high[2205..]) < 0.02);
+
+        let low = Resampler::new(48000, 22050, 1).process(&sine(1000.0, 48000, 48000));
+        assert!((rms(&low[2205..]) - 0.5_f32.sqrt()).abs() < 0.05);
+    }
+
+    #[test]
+    fn loop_regions_are_scaled_to_the_new_rate() {
+        let sound = Sound { data: vec![0.0; 400], sample_rate: 22050, channels: 1, loop_region: Some(LoopRegion { start: 100, end: Some(300) }) };
+        let resampled = resample(sound, 44100);
+        assert_eq!(resampled.sample_rate, 44100);
+        assert_eq!(resampled.loop_region, Some(LoopRegion { start: 200, end: Some(600) }));
+
+        let sound = Sound { data: vec![0.0; 400], sample_rate: 48000, channels: 1, loop_region: Some(LoopRegion { start: 4800, end: None }) };
+        assert_eq!(resample(sound, 44100).loop_region, Some(LoopRegion { start: 4410, end: None }));
+    }
+}
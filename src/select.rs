@@ -0,0 +1,153 @@
+use std::collections::VecDeque;
+
+use rand::Rng;
+
+// how a sound with several variants picks the one to play, set per sound in the manifest
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Selection {
+    Uniform,
+    Weighted, // by the manifest's weights, in variant order (missing weights are 1)
+    ShuffleBag, // every variant once in a random order, then reshuffle
+    RoundRobin, // in variant order
+    AvoidLast, // uniform but never one of the last avoid_last played
+}
+
+impl Default for Selection {
+    fn default() -> Selection {
+        Selection::Uniform
+    }
+}
+
+// what a sound's selection remembers between plays
+#[derive(Debug, Clone, Default)]
+pub struct SelectionState {
+    count: usize, // of variants when the state was built, it starts over if that changes
+    bag: Vec<usize>,
+    next: usize,
+    recent: VecDeque<usize>, // most recent first
+}
+
+impl SelectionState {
+    pub fn new() -> SelectionState {
+        SelectionState::default()
+    }
+
+    // an index below count, None when there's nothing to pick from
+    pub fn select<R: Rng>(&mut self, selection: Selection, weights: &[f32], avoid_last: usize, count: usize, rng: &mut R) -> Option<usize> {
+        if count == 0 {
+            return None;
+        }
+        if self.count != count {
+            *self = SelectionState { count: count, .. SelectionState::default() };
+        }
+
+        let index = match selection {
+            Selection::Uniform => rng.gen_range(0, count),
+            Selection::Weighted => weighted(weights, count, rng),
+            Selection::ShuffleBag => {
+                if self.bag.is_empty() {
+                    self.bag = (0..count).collect();
+                    rng.shuffle(&mut self.bag);
+                    // the bag is popped from the back, don't start the new round with the one that ended the last
+                    if count > 1 && self.bag.last() == self.recent.front() {
+                        self.bag.swap(0, count - 1);
+                    }
+                }
+                self.bag.pop().unwrap_or(0)
+            },
+            Selection::RoundRobin => {
+                let index = self.next % count;
+                self.next = index + 1;
+                index
+            },
+            Selection::AvoidLast => {
+                let avoid = avoid_last.min(count - 1);
+                let candidates : Vec<usize> = (0..count).filter(|i| !self.recent.iter().take(avoid).any(|r| r == i)).collect();
+                *rng.choose(&candidates).unwrap_or(&0)
+            },
+        };
+
+        self.recent.push_front(index);
+        self.recent.truncate(avoid_last.max(1));
+        Some(index)
+    }
+}
+
+fn weighted<R: Rng>(weights: &[f32], count: usize, rng: &mut R) -> usize {
+    let weight = |i: usize| weights.get(i).cloned().unwrap_or(1.0).max(0.0);
+    let total : f32 = (0..count).map(&weight).sum();
+    if total <= 0.0 {
+        return rng.gen_range(0, count);
+    }
+    let mut pick = rng.gen::<f32>() * total;
+    for i in 0..count {
+        pick -= weight(i);
+        if pick < 0.0 {
+            return i;
+        }
+    }
+    // rounding left some over, never land on a weight of 0
+    (0..count).rev().find(|&i| weight(i) > 0.0).unwrap_or(count - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::XorShiftRng;
+
+    use super::{Selection, SelectionState};
+
+    fn picks(selection: Selection, weights: &[f32], avoid_last: usize, count: usize, plays: usize) -> Vec<usize> {
+        let mut rng = XorShiftRng::new_unseeded();
+        let mut state = SelectionState::new();
+        (0..plays).map(|_| state.select(selection, weights, avoid_last, count, &mut rng).unwrap()).collect()
+    }
+
+    #[test]
+    fn shuffle_bags_play_every_variant_once_per_round_without_repeats() {
+        let picks = picks(Selection::ShuffleBag, &[], 1, 4, 100);
+        for round in picks.chunks(4) {
+            let mut round = round.to_vec();
+            round.sort();
+            assert_eq!(round, vec![0, 1, 2, 3]);
+        }
+        // including across refills
+        assert!(picks.windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn avoid_last_avoids_all_but_one_when_asked_for_more() {
+        let picks = picks(Selection::AvoidLast, &[], 5, 3, 100);
+        assert!(picks.windows(3).all(|three| three[0] != three[1] && three[0] != three[2] && three[1] != three[2]));
+
+        assert!(self::picks(Selection::AvoidLast, &[], 5, 1, 10).iter().all(|&i| i == 0));
+        assert!(self::picks(Selection::AvoidLast, &[], 1, 2, 100).windows(2).all(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn weights_of_zero_are_never_picked_and_missing_ones_are_one() {
+        assert!(picks(Selection::Weighted, &[0.0, 1.0, 0.0], 1, 3, 100).iter().all(|&i| i == 1));
+
+        // weights past the variants are ignored, missing ones count as 1
+        let short = picks(Selection::Weighted, &[0.0], 1, 3, 100);
+        assert!(short.iter().all(|&i| i == 1 || i == 2));
+        assert!(short.contains(&1) && short.contains(&2));
+        assert!(picks(Selection::Weighted, &[0.0, 2.0, 5.0, 7.0], 1, 2, 100).iter().all(|&i| i == 1));
+
+        // nothing with any weight is uniform
+        let none = picks(Selection::Weighted, &[0.0, -1.0], 1, 2, 100);
+        assert!(none.contains(&0) && none.contains(&1));
+    }
+
+    #[test]
+    fn round_robin_goes_in_order_and_starts_over_when_the_variants_change() {
+        assert_eq!(picks(Selection::RoundRobin, &[], 1, 3, 7), vec![0, 1, 2, 0, 1, 2, 0]);
+
+        let mut rng = XorShiftRng::new_unseeded();
+        let mut state = SelectionState::new();
+        assert_eq!(state.select(Selection::RoundRobin, &[], 1, 3, &mut rng), Some(0));
+        assert_eq!(state.select(Selection::RoundRobin, &[], 1, 3, &mut rng), Some(1));
+        assert_eq!(state.select(Selection::RoundRobin, &[], 1, 2, &mut rng), Some(0));
+        assert_eq!(state.select(Selection::RoundRobin, &[], 1, 0, &mut rng), None);
+    }
+}
@@ -1,13 +1,18 @@
-use alto::{Context, StaticSource, StreamingSource, Buffer, SourceTrait, Mono, Stereo};
-
-use std::fs::File;
-use std::path::PathBuf;
-
-use lewton;
-use lewton::inside_ogg::OggStreamReader;
+use std::path::{PathBuf, Path};
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::mem;
 
 use super::*;
 use errors::*;
+use backend::{SoundBackend, SourceBackend, StaticSourceBackend, StreamingSourceBackend};
+use effects::SourceEffects;
+use mix::BusMix;
+use load::{SoundStream, load_stream};
+use assets::AssetSource;
+use channels::{Multichannel, output_channels, convert_channels};
+use resample::Resampler;
+use manifest::Variation;
 
 // an index to a source + binding
 #[derive(Debug, Clone, Copy)]
@@ -21,15 +26,24 @@ pub struct SoundSourceLoan {
 pub struct SoundBinding {
     pub event_id: SoundEventId,
     pub sound_event: SoundEvent,
+    pub variation: Variation,
+    pub sound_gain: Gain, // the sound's own, its preload gain and loudness normalization
+}
+
+impl SoundBinding {
+    // what the source plays at under bus_mix
+    pub fn gain(&self, bus_mix: &BusMix) -> Gain {
+        self.sound_event.gain * self.variation.gain * self.sound_gain * bus_mix.gain
+    }
 }
 
-pub struct Sources<'d> {
+pub struct Sources<B: SoundBackend> {
     pub next_event: SoundEventId,
-    pub sources: Vec<SoundSource<'d>>, 
-    pub streaming: Vec<StreamingSoundSource<'d>>,
+    pub sources: Vec<SoundSource<B>>, 
+    pub streaming: Vec<StreamingSoundSource<B>>,
 }
 
-impl <'d> Sources<'d> {
+impl <B: SoundBackend> Sources<B> {
     pub fn next_event_id(&mut self) -> SoundEventId {
         self.next_event += 1;
         self.next_event
@@ -46,7 +60,7 @@ impl <'d> Sources<'d> {
         None
     }
 
-    pub fn loan_next_free_static<'a>(&'a mut self) -> Option<(&'a mut SoundSource<'d>, SoundSourceLoan)> {
+    pub fn loan_next_free_static<'a>(&'a mut self) -> Option<(&'a mut SoundSource<B>, SoundSourceLoan)> {
         let event_id = self.next_event_id();
         let first_free = self.sources.iter_mut().enumerate().find(|&(_, ref s)| s.current_binding.is_none());
         if let Some((idx, source)) = first_free {
@@ -61,7 +75,7 @@ impl <'d> Sources<'d> {
         }
     }
 
-    pub fn loan_next_free_streaming<'a>(&'a mut self) -> Option<(&'a mut StreamingSoundSource<'d>, SoundSourceLoan)> {
+    pub fn loan_next_free_streaming<'a>(&'a mut self) -> Option<(&'a mut StreamingSoundSource<B>, SoundSourceLoan)> {
         let event_id = self.next_event_id();
         let first_free = self.streaming.iter_mut().enumerate().find(|&(_, ref s)| s.current_binding.is_none());
         if let Some((idx, source)) = first_free {
@@ -77,10 +91,10 @@ impl <'d> Sources<'d> {
     }
 
     // I don't really understand this 'a on the mut self :-(
-    pub fn for_loan<'a>(&'a mut self, loan:SoundSourceLoan) -> Option<CombinedSource<'d, 'a>> {
+    pub fn for_loan<'a>(&'a mut self, loan:SoundSourceLoan) -> Option<CombinedSource<'a, B>> {
         use self::CombinedSource::*;
         if loan.streaming {
-            let mut source : &'a mut StreamingSoundSource<'d> = &mut self.streaming[loan.source_id];
+            let source : &'a mut StreamingSoundSource<B> = &mut self.streaming[loan.source_id];
             let valid = source.current_binding.iter().any(|ss| ss.event_id == loan.event_id );
             if valid {
                 Some(Streaming(source))
@@ -88,7 +102,7 @@ impl <'d> Sources<'d> {
                 None
             }
         } else {
-            let mut source : &'a mut SoundSource<'d> = &mut self.sources[loan.source_id];
+            let source : &'a mut SoundSource<B> = &mut self.sources[loan.source_id];
             let valid = source.current_binding.iter().any(|ss| ss.event_id == loan.event_id );
             if valid {
                 Some(Static(source))
@@ -108,9 +122,33 @@ impl <'d> Sources<'d> {
         Ok(())
     }
     
+    pub fn any_bound<F: Fn(&SoundEvent) -> bool>(&self, f: F) -> bool {
+        self.sources.iter().filter_map(|s| s.current_binding.as_ref()).any(|b| f(&b.sound_event)) ||
+            self.streaming.iter().filter_map(|s| s.current_binding.as_ref()).any(|b| f(&b.sound_event))
+    }
+
+    // re-sets the gain and effects of every bound source, for when the buses under them change
+    pub fn update_mix<F: Fn(&SoundEvent) -> BusMix>(&mut self, backend: &B, bus_mix: F) -> SoundProviderResult<()> {
+        for source in self.sources.iter_mut() {
+            if let Some(ref binding) = source.current_binding {
+                let mix = bus_mix(&binding.sound_event);
+                source.inner.set_gain(binding.gain(&mix))?;
+                backend.set_static_source_effects(&mut source.inner, &SourceEffects::for_event(&binding.sound_event, &mix))?;
+            }
+        }
+        for source in self.streaming.iter_mut() {
+            if let Some(ref binding) = source.current_binding {
+                let mix = bus_mix(&binding.sound_event);
+                source.inner.set_gain(binding.gain(&mix))?;
+                backend.set_streaming_source_effects(&mut source.inner, &SourceEffects::for_event(&binding.sound_event, &mix))?;
+            }
+        }
+        Ok(())
+    }
+
     // just updates book keeping of sources that have stopped since we checked (so we can throw away the binding)
     pub fn check_bindings(&mut self) -> SoundProviderResult<(u32, u32)> {
-        use alto::SourceState::*;
+        use backend::SourceState::*;
 
         let mut available_sources = 0;
         let mut available_streaming_sources = 0;
@@ -149,20 +187,23 @@ impl <'d> Sources<'d> {
 }
 
 
-pub struct SoundSource<'d> {
-    pub inner: StaticSource<'d, 'd>, // make this private at some point?
+pub struct SoundSource<B: SoundBackend> {
+    pub inner: B::StaticSource, // make this private at some point?
     pub current_binding: Option<SoundBinding>,
 }
 
-impl<'d> SoundSource<'d> {
+impl<B: SoundBackend> SoundSource<B> {
     // these perhaps should be implemented on their respective sources
-    pub fn assign_event(&mut self, sound_event: SoundEvent, event_id: SoundEventId) -> SoundProviderResult<()> {
-        assign_event_details(&mut self.inner, &sound_event)?;
-        self.inner.set_looping(sound_event.loop_sound)?;
-        self.current_binding = Some(SoundBinding {
+    pub fn assign_event(&mut self, backend: &B, sound_event: SoundEvent, variation: Variation, sound_gain: Gain, bus_mix: &BusMix, event_id: SoundEventId) -> SoundProviderResult<()> {
+        let binding = SoundBinding {
             event_id: event_id,
             sound_event: sound_event,
-        });
+            variation: variation,
+            sound_gain: sound_gain,
+        };
+        assign_event_details(&mut self.inner, &binding, bus_mix, |s, effects| backend.set_static_source_effects(s, effects))?;
+        self.inner.set_looping(binding.sound_event.loop_sound)?;
+        self.current_binding = Some(binding);
         Ok(())
     }
 
@@ -174,63 +215,178 @@ impl<'d> SoundSource<'d> {
     }
 }
 
-pub struct StreamingSoundSource<'d> {
-    pub inner: StreamingSource<'d, 'd>, // make this private at some point?
-    pub stream_reader : Option<(OggStreamReader<File>, PathBuf)>,
+// the most of a loop region a stream keeps, 4MB of f32 (about 12s of 44.1kHz stereo)
+pub const MAX_RECORDED_LOOP_SAMPLES : usize = 1 << 20;
+
+// a looping stream goes back to start whenever it reaches end
+// a short loop with an intro keeps the region's samples the first time through and plays it from those after, the intro isn't decoded again
+// one without an intro, one longer than MAX_RECORDED_LOOP_SAMPLES (or one that was skipped into) is reopened from its assets and read up to start
+pub struct StreamLoop {
+    pub assets: Arc<AssetSource>,
+    pub start: u64, // frames of the stream
+    pub end: Option<u64>, // None is the end of the stream
+    pub region: Vec<f32>, // interleaved, as read from the stream
+    pub recording: bool, // the stream's been read from start on into region
+    pub too_long: bool, // longer than MAX_RECORDED_LOOP_SAMPLES, it's reopened every loop
+    pub cursor: Option<usize>, // in region once loops play from it
+}
+
+impl StreamLoop {
+    pub fn new(assets: Arc<AssetSource>, start: u64, end: Option<u64>) -> StreamLoop {
+        StreamLoop { assets: assets, start: start, end: end, region: Vec::new(), recording: false, too_long: false, cursor: None }
+    }
+
+    // keeps what of data (read from the stream at frame position) is in the region
+    fn record(&mut self, position: u64, data: &[f32], channels: usize) {
+        let from = if self.recording {
+            0
+        } else {
+            let frames = (data.len() / channels) as u64;
+            if self.too_long || self.start == 0 || position > self.start || position + frames <= self.start {
+                return;
+            }
+            if self.end.map(|end| end.saturating_sub(self.start).saturating_mul(channels as u64) > MAX_RECORDED_LOOP_SAMPLES as u64).unwrap_or(false) {
+                self.too_long = true;
+                return;
+            }
+            (self.start - position) as usize * channels
+        };
+        if self.region.len() + (data.len() - from) > MAX_RECORDED_LOOP_SAMPLES {
+            self.too_long = true;
+            self.recording = false;
+            self.region = Vec::new();
+            return;
+        }
+        self.recording = true;
+        self.region.extend_from_slice(&data[from..]);
+    }
+
+    // appends the region from the cursor on until data holds samples
+    fn replay(&mut self, data: &mut Vec<f32>, samples: usize) {
+        let cursor = self.cursor.unwrap_or(0);
+        let end = (cursor + samples.saturating_sub(data.len())).min(self.region.len());
+        data.extend_from_slice(&self.region[cursor..end]);
+        self.cursor = Some(end);
+    }
+}
+
+pub struct StreamingSoundSource<B: SoundBackend> {
+    pub inner: B::StreamingSource, // make this private at some point?
+    pub stream_reader : Option<(SoundStream, PathBuf)>,
+    pub stream_loop: Option<StreamLoop>, // None plays the stream through once
+    pub stream_position: u64, // frames read from the stream
+    pub carry: Vec<f32>, // read past the end of the last buffer, the next one starts with it
+    pub multichannel: Multichannel, // for the stream being read
+    pub resampler: Option<Resampler>, // when it's converted to another rate
     pub current_binding: Option<SoundBinding>,
+    pub queued_durations: VecDeque<f32>, // of each buffer still queued, oldest first
+    pub played_duration: f32, // of the stream up to the first buffer still queued
 }
 
 const BUFFERS_TO_QUEUE: usize = 5;
 
-impl<'d> StreamingSoundSource<'d> {
-    pub fn assign_event(&mut self, sound_event: SoundEvent, event_id: SoundEventId) -> SoundProviderResult<()> {
-        assign_event_details(&mut self.inner, &sound_event)?;
-        self.current_binding = Some(SoundBinding {
+impl<B: SoundBackend> StreamingSoundSource<B> {
+    pub fn assign_event(&mut self, backend: &B, sound_event: SoundEvent, variation: Variation, sound_gain: Gain, bus_mix: &BusMix, event_id: SoundEventId) -> SoundProviderResult<()> {
+        let binding = SoundBinding {
             event_id: event_id,
             sound_event: sound_event,
-        });
+            variation: variation,
+            sound_gain: sound_gain,
+        };
+        assign_event_details(&mut self.inner, &binding, bus_mix, |s, effects| backend.set_streaming_source_effects(s, effects))?;
+        self.current_binding = Some(binding);
         Ok(())
     }
 
-    pub fn ensure_buffers_queued(&mut self, context: &'d Context<'d>, buffer_duration: f32) -> PreloadResult<()> {
+    pub fn ensure_buffers_queued(&mut self, backend: &B, buffer_duration: f32) -> PreloadResult<()> {
+        let mut empty_rewinds = 0; // in a row, a loop that gives nothing back is played through once instead
         loop {
             let queued = self.inner.buffers_queued()?;
             let processed = self.inner.buffers_processed()?;
             // println!("queued count {:?}", queued);
-            if queued < BUFFERS_TO_QUEUE as i32 || processed > 0 {
+            if queued < BUFFERS_TO_QUEUE || processed > 0 {
                 // println!("not enough buffers!");
                 let eof_cleanup : bool = if let Some((ref mut reader, ref path)) = self.stream_reader {
                     // 1 for 1 is retarded
-                    let channels = reader.ident_hdr.audio_channels;
-                    let sample_rate = reader.ident_hdr.audio_sample_rate;
-                    let mut data : Vec<i16> = Vec::new();
+                    let channels = reader.channels();
+                    let sample_rate = reader.sample_rate();
 
                     // per pack
-                    let samples_to_drain : usize = (sample_rate as f32 * buffer_duration / (BUFFERS_TO_QUEUE as f32)) as usize;
-
-                    let samples_read = drain(reader, &mut data, samples_to_drain).map_err(|oe| LoadError { path: path.clone(), reason: LoadErrorReason::ReadOggError(oe)})?;
-                    let eof = samples_read < samples_to_drain;
+                    let mut samples_to_drain : usize = (sample_rate as f32 * buffer_duration / (BUFFERS_TO_QUEUE as f32)) as usize;
+                    // buffers stop at the loop end
+                    if let Some(end) = self.stream_loop.as_ref().and_then(|l| l.end) {
+                        samples_to_drain = samples_to_drain.min(end.saturating_sub(self.stream_position) as usize * channels as usize);
+                    }
+
+                    let mut data : Vec<f32> = mem::replace(&mut self.carry, Vec::new());
+                    match self.stream_loop {
+                        Some(ref mut stream_loop) if stream_loop.cursor.is_some() => stream_loop.replay(&mut data, samples_to_drain),
+                        _ => {
+                            reader.read(&mut data, samples_to_drain).map_err(|reason| LoadError { path: path.clone(), reason: reason })?;
+                        },
+                    }
+                    let excess = if data.len() > samples_to_drain { data.split_off(samples_to_drain) } else { Vec::new() };
+                    let mut eof = data.len() < samples_to_drain;
+                    if let Some(ref mut stream_loop) = self.stream_loop {
+                        if stream_loop.cursor.is_none() {
+                            stream_loop.record(self.stream_position, &data, channels as usize);
+                        }
+                    }
+                    self.stream_position += (data.len() / channels as usize) as u64;
+
+                    let loop_end = match self.stream_loop {
+                        Some(ref stream_loop) => eof || stream_loop.end.map(|end| self.stream_position >= end).unwrap_or(false),
+                        None => false,
+                    };
+                    if loop_end {
+                        empty_rewinds = if data.is_empty() { empty_rewinds + 1 } else { 0 };
+                        let replay = self.stream_loop.as_ref().map(|l| l.recording || l.cursor.is_some()).unwrap_or(false);
+                        if empty_rewinds > 1 {
+                            // a loop with nothing in it
+                            self.stream_loop = None;
+                        } else if replay {
+                            let stream_loop = self.stream_loop.as_mut().unwrap();
+                            stream_loop.recording = false;
+                            stream_loop.cursor = Some(0);
+                            self.stream_position = stream_loop.start;
+                            eof = false;
+                        } else {
+                            let (stream, carry, position) = rewind(path, self.stream_loop.as_ref().unwrap())?;
+                            // a loop starting past the end of the stream
+                            if position < self.stream_loop.as_ref().map(|l| l.start).unwrap_or(0) {
+                                self.stream_loop = None;
+                            } else {
+                                *reader = stream;
+                                self.carry = carry;
+                                self.stream_position = position;
+                                eof = false;
+                            }
+                        }
+                    } else {
+                        self.carry = excess;
+                    }
 
                     if data.len() > 0 {
-                        let mut buffer : Buffer = if self.inner.buffers_processed()? > 0 {
+                        let mut buffer : B::Buffer = if self.inner.buffers_processed()? > 0 {
+                            self.played_duration += self.queued_durations.pop_front().unwrap_or(0.0);
                             self.inner.unqueue_buffer()?
                         } else {
-                            context.new_buffer()?
+                            backend.new_buffer()?
                         };
 
-                        // let duration = (data.len() as f32) / (sample_rate as f32);
-                        
-                        if channels == 1 {
-                            buffer.set_data::<Mono<i16>, _>(data, sample_rate as i32)?;
-                        } else if channels == 2 {
-                            buffer.set_data::<Stereo<i16>, _>(data, sample_rate as i32)?;
-                        } else {
-                            return Err(PreloadError::LoadError(LoadError { path: path.clone(), reason: LoadErrorReason::TooManyChannels }));
-                        }
+                        let (data, sample_rate) = match self.resampler {
+                            Some(ref mut resampler) => (resampler.process(&data), resampler.to),
+                            None => (data, sample_rate),
+                        };
+                        let duration = (data.len() as f32) / (channels as f32) / (sample_rate as f32);
+
+                        let buffer_channels = output_channels(channels, self.multichannel, |c| backend.supports_channels(c));
+                        let data = convert_channels(data, channels, buffer_channels);
+                        backend.set_buffer_data(&mut buffer, data, buffer_channels, sample_rate)?;
 
                         match self.inner.queue_buffer(buffer) {
-                            Ok(()) => (),
-                            Err((error, _)) => {
+                            Ok(()) => self.queued_durations.push_back(duration),
+                            Err(error) => {
                                 println!("no queued buffer fml");   
                                 return Err(error.into())
                             },
@@ -254,9 +410,51 @@ impl<'d> StreamingSoundSource<'d> {
         Ok(())
     }
 
+    // seconds in to the stream
+    pub fn offset(&self) -> SoundProviderResult<f32> {
+        Ok(self.played_duration + self.inner.offset()?)
+    }
+
+    // decodes and throws away offset seconds of the stream, call before any buffers are queued
+    // set stream_loop first, offsets past the loop's end land inside it
+    pub fn skip(&mut self, offset: f32) -> PreloadResult<()> {
+        if let Some((ref mut reader, ref path)) = self.stream_reader {
+            let channels = reader.channels() as usize;
+            let sample_rate = reader.sample_rate();
+            let mut frames = ((sample_rate as f32) * offset) as u64;
+            if let Some(ref stream_loop) = self.stream_loop {
+                if let Some(end) = stream_loop.end {
+                    if frames >= end && end > stream_loop.start {
+                        frames = stream_loop.start + (frames - end) % (end - stream_loop.start);
+                    }
+                }
+            }
+            let samples = frames as usize * channels;
+
+            let mut skipped : Vec<f32> = Vec::new();
+            reader.read(&mut skipped, samples).map_err(|reason| LoadError { path: path.clone(), reason: reason })?;
+            if skipped.len() > samples {
+                self.carry = skipped.split_off(samples);
+            }
+            self.stream_position = (skipped.len() / channels) as u64;
+            self.played_duration = (skipped.len() / channels) as f32 / (sample_rate as f32);
+        }
+        Ok(())
+    }
+
+    pub fn reset_position(&mut self) {
+        self.queued_durations.clear();
+        self.played_duration = 0.0;
+        self.stream_position = 0;
+        self.carry.clear();
+    }
+
     pub fn clean(&mut self) -> SoundProviderResult<()> {
         self.stream_reader = None;
+        self.stream_loop = None;
+        self.resampler = None;
         self.current_binding = None;
+        self.reset_position();
         self.inner.stop()?;
         while self.inner.buffers_queued()? > 0 {
             self.inner.unqueue_buffer()?;
@@ -265,49 +463,82 @@ impl<'d> StreamingSoundSource<'d> {
     }
 }
 
-fn drain(reader: &mut OggStreamReader<File>, data: &mut Vec<i16>, samples: usize) -> Result<usize, lewton::VorbisError> {
-    use std::iter::Extend;
-
-    let mut samples_read : usize = 0;
-    while data.len() < samples {
-        if let Some(packet) = reader.read_dec_packet_itl()? {
-            samples_read += packet.len();
-            data.extend(&packet);
-        } else {
-            break;
-        }
-    }
-
-    Ok(samples_read)
+// a fresh stream read up to the loop start, with what was read past it and the frame it's at (short of start if the stream ends first)
+fn rewind(path: &Path, stream_loop: &StreamLoop) -> PreloadResult<(SoundStream, Vec<f32>, u64)> {
+    let mut stream = load_stream(&*stream_loop.assets, path)?;
+    let channels = stream.channels() as usize;
+    let samples = stream_loop.start as usize * channels;
+    let mut skipped : Vec<f32> = Vec::new();
+    stream.read(&mut skipped, samples).map_err(|reason| LoadError { path: path.to_path_buf(), reason: reason })?;
+    let carry = if skipped.len() > samples { skipped.split_off(samples) } else { Vec::new() };
+    let position = (skipped.len() / channels.max(1)) as u64;
+    Ok((stream, carry, position))
 }
 
-pub fn assign_event_details<'d: 'c, 'c, ST : SourceTrait<'d,'c>>(source: &mut ST, sound_event:&SoundEvent) -> SoundProviderResult<()> {
-    source.set_pitch(sound_event.pitch)?;
-    source.set_position(sound_event.position)?;
-    source.set_gain(sound_event.gain)?;
+// bus_mix is the event's bus combined with everything above it
+// effects (reverb send, lowpass) go through the backend, set_effects is the static or streaming variant of it
+pub fn assign_event_details<S, F>(source: &mut S, binding: &SoundBinding, bus_mix: &BusMix, set_effects: F) -> SoundProviderResult<()>
+    where S : SourceBackend, F : FnOnce(&mut S, &SourceEffects) -> SoundProviderResult<()> {
+    source.set_pitch(binding.sound_event.pitch * binding.variation.pitch)?;
+    source.set_position(binding.sound_event.position)?;
+    source.set_gain(binding.gain(bus_mix))?;
+    set_effects(source, &SourceEffects::for_event(&binding.sound_event, bus_mix))?;
     Ok(())
 }
 
 // used for retrieving loans
-pub enum CombinedSource<'d: 'a, 'a> {
-    Static(&'a mut SoundSource<'d>),
-    Streaming(&'a mut StreamingSoundSource<'d>),
+pub enum CombinedSource<'a, B: SoundBackend + 'a> {
+    Static(&'a mut SoundSource<B>),
+    Streaming(&'a mut StreamingSoundSource<B>),
 }
 
-impl<'d: 'a, 'a> CombinedSource<'d, 'a> {
-    pub fn assign_event(&mut self, event:SoundEvent, event_id: SoundEventId) -> SoundProviderResult<()> {
+impl<'a, B: SoundBackend + 'a> CombinedSource<'a, B> {
+    pub fn assign_event(&mut self, backend: &B, event:SoundEvent, variation: Variation, sound_gain: Gain, bus_mix: &BusMix, event_id: SoundEventId) -> SoundProviderResult<()> {
         use self::CombinedSource::*;
         match self {
             &mut Static(ref mut source) => {
-                source.assign_event(event, event_id)?;
+                source.assign_event(backend, event, variation, sound_gain, bus_mix, event_id)?;
             },
             &mut Streaming(ref mut source) => {
-                source.assign_event(event, event_id)?;
+                source.assign_event(backend, event, variation, sound_gain, bus_mix, event_id)?;
             },
         }
         Ok(())
     }
 
+    pub fn sound_event(&self) -> Option<&SoundEvent> {
+        use self::CombinedSource::*;
+        let binding = match self {
+            &Static(ref source) => source.current_binding.as_ref(),
+            &Streaming(ref source) => source.current_binding.as_ref(),
+        };
+        binding.map(|b| &b.sound_event)
+    }
+
+    fn binding(&self) -> Option<&SoundBinding> {
+        use self::CombinedSource::*;
+        match self {
+            &Static(ref source) => source.current_binding.as_ref(),
+            &Streaming(ref source) => source.current_binding.as_ref(),
+        }
+    }
+
+    pub fn variation(&self) -> Variation {
+        self.binding().map(|b| b.variation).unwrap_or(Variation::none())
+    }
+
+    pub fn sound_gain(&self) -> Gain {
+        self.binding().map(|b| b.sound_gain).unwrap_or(1.0)
+    }
+
+    pub fn offset(&self) -> SoundProviderResult<f32> {
+        use self::CombinedSource::*;
+        match self {
+            &Static(ref source) => source.inner.offset(),
+            &Streaming(ref source) => source.offset(),
+        }
+    }
+
     pub fn stop(&mut self) -> SoundProviderResult<()> {
         use self::CombinedSource::*;
         match self {
@@ -319,7 +550,9 @@ impl<'d: 'a, 'a> CombinedSource<'d, 'a> {
             &mut Streaming(ref mut source) => {
                 source.inner.stop()?;
                 source.stream_reader = None;
+                source.stream_loop = None;
                 source.current_binding = None;
+                source.reset_position();
                 while source.inner.buffers_processed()? > 0 {
                     source.inner.unqueue_buffer()?;
                 }
@@ -327,4 +560,62 @@ impl<'d: 'a, 'a> CombinedSource<'d, 'a> {
         }
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use assets::FileSystemAssets;
+    use super::{StreamLoop, MAX_RECORDED_LOOP_SAMPLES};
+
+    #[test]
+    fn loop_regions_are_recorded_from_start_and_replayed() {
+        let mut stream_loop = StreamLoop::new(Arc::new(FileSystemAssets::new("")), 3, Some(6));
+
+        // stereo chunks of 2 frames, the intro's frames 0..3 aren't kept
+        stream_loop.record(0, &[0.0, 0.0, 1.0, 1.0], 2);
+        assert!(!stream_loop.recording);
+        stream_loop.record(2, &[2.0, 2.0, 3.0, 3.0], 2);
+        stream_loop.record(4, &[4.0, 4.0, 5.0, 5.0], 2);
+        assert_eq!(stream_loop.region, vec![3.0, 3.0, 4.0, 4.0, 5.0, 5.0]);
+
+        stream_loop.cursor = Some(0);
+        let mut data = vec![3.0, 3.0]; // carried over
+        stream_loop.replay(&mut data, 4);
+        assert_eq!(data, vec![3.0, 3.0, 3.0, 3.0]);
+        let mut data = Vec::new();
+        stream_loop.replay(&mut data, 8);
+        assert_eq!(data, vec![4.0, 4.0, 5.0, 5.0]);
+        assert_eq!(stream_loop.cursor, Some(6));
+    }
+
+    #[test]
+    fn loops_too_long_to_keep_arent_recorded() {
+        let assets = Arc::new(FileSystemAssets::new(""));
+
+        // known to be too long from the start
+        let mut stream_loop = StreamLoop::new(assets.clone(), 1, Some(1 + MAX_RECORDED_LOOP_SAMPLES as u64));
+        stream_loop.record(0, &[0.0, 1.0, 2.0, 3.0], 2);
+        assert!(stream_loop.too_long && !stream_loop.recording);
+
+        // or found to be as it's read, what was kept is let go and it's not tried again
+        let mut stream_loop = StreamLoop::new(assets, 1, None);
+        stream_loop.record(0, &vec![0.0; MAX_RECORDED_LOOP_SAMPLES], 1);
+        assert!(stream_loop.recording);
+        stream_loop.record(MAX_RECORDED_LOOP_SAMPLES as u64, &[0.0, 0.0], 1);
+        assert!(stream_loop.too_long && !stream_loop.recording && stream_loop.region.capacity() == 0);
+        stream_loop.record(0, &[0.0, 0.0], 1);
+        assert!(!stream_loop.recording);
+    }
+
+    #[test]
+    fn loops_skipped_into_or_without_an_intro_arent_recorded() {
+        let mut stream_loop = StreamLoop::new(Arc::new(FileSystemAssets::new("")), 2, None);
+        stream_loop.record(3, &[3.0, 4.0], 1);
+        assert!(!stream_loop.recording && stream_loop.region.is_empty());
+
+        let mut stream_loop = StreamLoop::new(Arc::new(FileSystemAssets::new("")), 0, None);
+        stream_loop.record(0, &[0.0, 1.0], 1);
+        assert!(!stream_loop.recording && stream_loop.region.is_empty());
+    }
+}
@@ -1,20 +1,113 @@
-
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Duration;
 use std::sync::mpsc::channel;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError};
 use std::sync::mpsc::SendError;
+use std::sync::Arc;
 
 use alto::Alto;
 use notify::{RecommendedWatcher, Watcher, RecursiveMode, RawEvent};
 
 use rand;
+use time;
+
+use super::engine::{SoundEngineUpdate, SoundEngineReport, SoundEngine, SuspendedSound};
+use super::context::{SoundContext, ContextRestore};
+use super::backend::{SoundBackend, ContextAttributes, HrtfStatus};
+use super::openal::{OpenALBackend, output_device_names, open_output, opened_device_name, context_attrs, granted_attributes};
+use super::mixer::{MixerBackend, MIXER_CHANNELS};
+use super::errors::PreloadError;
+use super::assets::{AssetSource, FileSystemAssets};
+use super::decode::{DecodePool, DecodePolicy};
+use WorkerResult;
+
+pub const MIXER_DEVICE_NAME : &'static str = "software mixer";
+
+// used by the software mixer when no frequency is requested
+pub const DEFAULT_SAMPLE_RATE : u32 = 44100;
 
-use super::engine::{SoundEngineUpdate, SoundEngine};
+// how often the workers tick (ducking, and the software mixer renders and refills streams) when no updates arrive
+const TICK_MS : u64 = 10;
 
+// how many times (and how far apart) the default device is retried when it can't be opened or refuses a context
+const DEVICE_RETRIES : u32 = 5;
+const DEVICE_RETRY_MS : u64 = 500;
+
+// everything a worker is created with, new fills in the defaults
+#[derive(Clone)]
+pub struct SoundWorkerConfig {
+    pub device: Option<String>, // one of SoundWorker::output_devices, None for the default output
+    pub resources_path: String, // watched for changes (empty watches nothing), and where sounds are read from unless assets is set
+    pub assets: Option<Arc<AssetSource>>, // e.g. a pack file, None reads the files under resources_path
+    pub extensions: Vec<String>, // e.g. ogg and wav, the first one wins when a sound has several
+    pub rng: rand::XorShiftRng,
+    pub streaming_threshold: u64, // files bigger than this (in bytes) are streamed
+    pub streaming_buffer_duration: f32,
+    pub resample_rate: Option<u32>, // converts every sound to this rate at load time, None keeps their own
+    pub context_attributes: ContextAttributes, // requested, the granted ones are reported back
+    pub static_sources: usize, // upper bounds, the granted source limits can lower these
+    pub streaming_sources: usize,
+    pub decode_threads: usize, // sounds played before they're loaded are decoded on these, 0 decodes them on the worker
+    pub decode_policy: DecodePolicy, // for one shot events whose sound is still decoding
+    pub loudness_target: Option<f32>, // LUFS (e.g. -23.0) static sounds are normalized to, None plays them as they are
+}
+
+impl SoundWorkerConfig {
+    pub fn new(resources_path: String, extensions: Vec<String>, rng: rand::XorShiftRng) -> SoundWorkerConfig {
+        SoundWorkerConfig {
+            device: None,
+            resources_path: resources_path,
+            assets: None,
+            extensions: extensions,
+            rng: rng,
+            streaming_threshold: 1_000_000,
+            streaming_buffer_duration: 5.0,
+            resample_rate: None,
+            context_attributes: ContextAttributes::default(),
+            static_sources: 32,
+            streaming_sources: 4,
+            decode_threads: 2,
+            decode_policy: DecodePolicy::default(),
+            loudness_target: None,
+        }
+    }
+
+    pub fn asset_source(&self) -> Arc<AssetSource> {
+        match self.assets {
+            Some(ref assets) => assets.clone(),
+            None => Arc::new(FileSystemAssets::new(self.resources_path.clone())),
+        }
+    }
+
+    pub fn apply_decoding<B: SoundBackend>(&self, cb: &mut SoundContext<B>) {
+        cb.decode_policy = self.decode_policy;
+        if self.decode_threads > 0 {
+            cb.decoder = Some(DecodePool::new(self.decode_threads, cb.assets.clone(), self.resample_rate));
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.context_attributes.frequency.unwrap_or(DEFAULT_SAMPLE_RATE)
+    }
+
+    // (static, streaming) pool sizes that fit within the granted source limits, streaming sources first
+    // the mono/stereo split is only a hint (OpenAL Soft grants 1 stereo source by default), so only their total caps the pools
+    pub fn source_counts(&self, granted: &ContextAttributes) -> (usize, usize) {
+        match (granted.mono_sources, granted.stereo_sources) {
+            (Some(mono), Some(stereo)) => {
+                let total = mono as usize + stereo as usize;
+                let streaming = self.streaming_sources.min(total);
+                (self.static_sources.min(total - streaming), streaming)
+            },
+            _ => (self.static_sources, self.streaming_sources),
+        }
+    }
+}
 
 pub struct SoundWorker {
     send_channel: Sender<SoundEngineUpdate>,
+    report_channel: Receiver<SoundEngineReport>,
     join_handle: JoinHandle<()>,
 }
 
@@ -23,6 +116,17 @@ impl SoundWorker {
         self.send_channel.send(update)
     }
 
+    // everything the worker has reported since the last call
+    pub fn reports(&self) -> Vec<SoundEngineReport> {
+        self.report_channel.try_iter().collect()
+    }
+
+    // names usable as the device for SoundWorker::create
+    pub fn output_devices(open_al_path: String) -> WorkerResult<Vec<String>> {
+        let alto = Alto::load(open_al_path)?;
+        Ok(output_device_names(&alto))
+    }
+
     pub fn shutdown_and_wait(self) {
         // println!("sending stop");
         self.send(SoundEngineUpdate::Stop).unwrap();
@@ -31,71 +135,361 @@ impl SoundWorker {
         // println!("thread joined");
     }
 
-    pub fn create(open_al_path: String, resources_path:String, extension:String, rng: rand::XorShiftRng, streaming_threshold: u64, streaming_buffer_duration: f32) -> SoundWorker {
+    // if the device is lost (or another is picked with SoundEngineUpdate::Device) the context is rebuilt, buffers reloaded and persistent sounds resumed
+    pub fn create(open_al_path: String, config: SoundWorkerConfig) -> SoundWorker {
         let (tx, rx) = channel::<SoundEngineUpdate>();
+        let (report_tx, report_rx) = channel::<SoundEngineReport>();
         let join_handle = thread::spawn(move || {
             let alto = Alto::load(open_al_path).unwrap();
-            let dev = alto.open(None).unwrap();
-            let ctx = dev.new_context(None).unwrap();
 
-            let mut cb = super::context::create_sound_context(&ctx, &resources_path, &extension, rng, streaming_threshold, streaming_buffer_duration);
+            let (_watcher, notify_rx) = watch_resources(&config.resources_path, &report_tx);
+
+            let mut engine = SoundEngine::new();
+            let mut device = config.device.clone();
+            let mut rng = config.rng.clone();
+            let mut restore : Option<(ContextRestore, Vec<SuspendedSound>)> = None;
+            let mut pending : Option<SoundEngineUpdate> = None; // arrived as the device was lost
+
+            let mut failures = 0; // in a row, of opening a device and creating a context on it
+
+            'device: loop {
+                let dev = match open_output(&alto, device.as_ref().map(|d| d.as_str())) {
+                    Ok(dev) => dev,
+                    Err(err) => {
+                        println!("Sound worker couldn't open an output device {:?}", err);
+                        let _ = report_tx.send(SoundEngineReport::DeviceFailed(device.clone().unwrap_or_else(|| String::from("default"))));
+                        if retry_device(&mut device, &mut failures) {
+                            continue 'device;
+                        } else {
+                            break 'device;
+                        }
+                    },
+                };
+                let device_name = opened_device_name(&dev);
+                // a device can refuse the requested attributes, or be one that's only just come back and refuse any context
+                let ctx = match dev.new_context(Some(context_attrs(&config.context_attributes))).or_else(|err| {
+                    println!("Sound worker couldn't create a context on {:?} with {:?} err -> {:?}, trying without", device_name, config.context_attributes, err);
+                    dev.new_context(None)
+                }) {
+                    Ok(ctx) => ctx,
+                    Err(err) => {
+                        println!("Sound worker couldn't create a context on {:?} err -> {:?}", device_name, err);
+                        let _ = report_tx.send(SoundEngineReport::DeviceFailed(device_name));
+                        if retry_device(&mut device, &mut failures) {
+                            continue 'device;
+                        } else {
+                            break 'device;
+                        }
+                    },
+                };
+                let granted = granted_attributes(&alto, &dev);
 
-            cb.create(32, 4).unwrap();
+                let _ = report_tx.send(SoundEngineReport::Device(device_name.clone()));
+                let _ = report_tx.send(SoundEngineReport::ContextAttributes(granted));
+
+                let mut cb = super::context::create_sound_context(OpenALBackend::new(&ctx, config.context_attributes), config.asset_source(), &config.extensions, rng.clone(), config.streaming_threshold, config.streaming_buffer_duration, config.resample_rate);
+
+                config.apply_decoding(&mut cb);
+                cb.loudness_target = config.loudness_target;
+
+                let (static_count, streaming_count) = config.source_counts(&granted);
+                if let Err(err) = cb.create(static_count, streaming_count) {
+                    if device_lost(&cb) {
+                        println!("Sound worker lost {:?} while creating sources err -> {:?}", device_name, err);
+                        let _ = report_tx.send(SoundEngineReport::DeviceFailed(device_name));
+                        if retry_device(&mut device, &mut failures) {
+                            continue 'device;
+                        } else {
+                            break 'device;
+                        }
+                    }
+                    // carries on with the sources it did get
+                    println!("Sound worker only created {} static and {} streaming sources err -> {:?}", cb.sources.sources.len(), cb.sources.streaming.len(), err);
+                }
+                failures = 0;
+
+                if let Some((context_restore, suspended)) = restore.take() {
+                    let restored = cb.restore(context_restore).and_then(|_| engine.resume(&mut cb, suspended));
+                    if let Err(err) = restored {
+                        println!("Sound engine received unrecoverable error {:?} while restoring and is shutting down", err);
+                        break 'device;
+                    }
+                }
+
+                let _ = report_tx.send(SoundEngineReport::HrtfProfiles(cb.backend.hrtf_profiles().unwrap_or(Vec::new())));
+                let _ = report_tx.send(SoundEngineReport::Hrtf(cb.backend.hrtf_status().unwrap_or(HrtfStatus::Unsupported)));
+
+                let action = loop {
+                    let next = match pending.take() {
+                        Some(event) => Ok(event),
+                        None => rx.recv_timeout(Duration::from_millis(TICK_MS)),
+                    };
+                    let processed = match next {
+                        Ok(event) => process_update(&mut engine, &mut cb, &notify_rx, &report_tx, event),
+                        Err(RecvTimeoutError::Timeout) => WorkerAction::Continue,
+                        Err(recv_error) => {
+                            println!("Sound worker received error when reading from channel {:?}", recv_error);
+                            break WorkerAction::Shutdown;
+                        },
+                    };
+                    match processed {
+                        WorkerAction::Continue => match tick(&mut engine, &mut cb) {
+                            WorkerAction::Continue => (),
+                            action => break action,
+                        },
+                        action => break action,
+                    }
+                };
+
+                match action {
+                    WorkerAction::SwitchDevice(next_device) => {
+                        device = next_device;
+                    },
+                    WorkerAction::DeviceLost(unprocessed) => {
+                        pending = unprocessed;
+                        let _ = report_tx.send(SoundEngineReport::DeviceLost(device_name));
+                    },
+                    _ => break 'device,
+                }
+
+                // the old context and everything loaned from it drops at the end of this iteration
+                restore = Some((cb.restore_point(), engine.suspend(time::precise_time_ns())));
+                rng = cb.rng.clone();
+            }
+        });
+
+        SoundWorker {
+            send_channel: tx,
+            report_channel: report_rx,
+            join_handle: join_handle,
+        }
+    }
+
+    // runs the whole pipeline on the software mixer, no OpenAL library or device required
+    // mixed interleaved stereo frames are handed to output in real time (pass a no-op to discard them)
+    // the mixer renders at config.sample_rate(), the device is ignored
+    pub fn create_mixer(config: SoundWorkerConfig, mut output: Box<FnMut(&[f32]) + Send>) -> SoundWorker {
+        let (tx, rx) = channel::<SoundEngineUpdate>();
+        let (report_tx, report_rx) = channel::<SoundEngineReport>();
+        let join_handle = thread::spawn(move || {
+            let sample_rate = config.sample_rate();
+            let granted = ContextAttributes { frequency: Some(sample_rate), hrtf: Some(false), .. ContextAttributes::default() };
 
-            let (notify_tx, notify_rx) = channel::<RawEvent>();
-            let mut watcher : RecommendedWatcher = Watcher::new_raw(notify_tx).expect("a watcher");
-            watcher.watch(&resources_path, RecursiveMode::Recursive).expect("watching shader vertex path");
+            let _ = report_tx.send(SoundEngineReport::Device(String::from(MIXER_DEVICE_NAME)));
+            let _ = report_tx.send(SoundEngineReport::ContextAttributes(granted));
+
+            let mut cb = super::context::create_sound_context(MixerBackend::new(sample_rate), config.asset_source(), &config.extensions, config.rng.clone(), config.streaming_threshold, config.streaming_buffer_duration, config.resample_rate);
+
+            config.apply_decoding(&mut cb);
+            cb.loudness_target = config.loudness_target;
+
+            let (static_count, streaming_count) = config.source_counts(&granted);
+            if let Err(err) = cb.create(static_count, streaming_count) {
+                println!("Sound worker only created {} static and {} streaming sources err -> {:?}", cb.sources.sources.len(), cb.sources.streaming.len(), err);
+            }
+
+            let (_watcher, notify_rx) = watch_resources(&config.resources_path, &report_tx);
 
             let mut engine = SoundEngine::new();
+            let mut mixed_until = time::precise_time_ns();
+            let mut frames : Vec<f32> = Vec::new();
             loop {
-                match rx.recv() {
+                match rx.recv_timeout(Duration::from_millis(TICK_MS)) {
                     Ok(event) => {
-                        // println!("worker receiving event {:?}", event);
- 						
- 						let mut purge = false;
- 						'fs: loop {
-	 						match notify_rx.try_recv() {
-	                			Ok(RawEvent { path, op:_, cookie:_ }) => {
-	                				println!("sound worker noticed path changed -> {:?}", path);
-	                				purge = true;
-	                			}
-	                			Err(_) => {
-	                				break 'fs;
-	                			}
-	                		}
-	                	}
-
-	                	if purge {
-	                		// at some point we could do smarter purging
-	                		println!("sound worker noticed file system changes, purging buffers");
-	        				if engine.process(&mut cb, SoundEngineUpdate::Clear).is_err() {
-                                break;
-                            }
-	                	}
-
-                        match engine.process(&mut cb, event) {
-                            Ok(true) => (),
-                            Ok(false) => {
-                                // println!("Sound engine shutting down");
-                                break;
-                            },
-                            Err(err) => {
-                                println!("Sound engine received unrecoverable error {:?} and is shutting down", err);
-                                break;
-                            },
+                        match process_update(&mut engine, &mut cb, &notify_rx, &report_tx, event) {
+                            WorkerAction::Continue => (),
+                            WorkerAction::SwitchDevice(_) => println!("Sound worker is running the software mixer, ignoring device switch"),
+                            _ => break,
                         }
                     },
-                    Err(recv_error) => {
-                        println!("Sound worker received error when reading from channel {:?}", recv_error);
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => {
+                        println!("Sound worker channel disconnected");
                         break;
                     },
                 }
+
+                match tick(&mut engine, &mut cb) {
+                    WorkerAction::Continue => (),
+                    _ => break,
+                }
+
+                // streams would otherwise only refill on render
+                match cb.ensure_buffers_queued() {
+                    Ok(_) => (),
+                    Err(PreloadError::LoadError(le)) => println!("Sound worker received load error while ensuring buffers are queued {:?}", le),
+                    Err(PreloadError::SoundProviderError(sp)) => {
+                        println!("Sound engine received unrecoverable error {:?} and is shutting down", sp);
+                        break;
+                    },
+                }
+
+                let now = time::precise_time_ns();
+                let frame_count = ((now - mixed_until) * sample_rate as u64 / 1_000_000_000) as usize;
+                mixed_until += frame_count as u64 * 1_000_000_000 / sample_rate as u64;
+
+                frames.clear();
+                frames.resize(frame_count * MIXER_CHANNELS, 0.0);
+                cb.backend.mix(&mut frames);
+                output(&frames);
             }
         });
 
         SoundWorker {
             send_channel: tx,
+            report_channel: report_rx,
             join_handle: join_handle,
         }
     }
-}
\ No newline at end of file
+}
+
+// nothing's watched without a resources path (e.g. sounds from a pack file), a watch that fails is reported and the worker carries on without it
+fn watch_resources(resources_path: &str, report_tx: &Sender<SoundEngineReport>) -> (Option<RecommendedWatcher>, Receiver<RawEvent>) {
+    let (notify_tx, notify_rx) = channel::<RawEvent>();
+    if resources_path.is_empty() {
+        return (None, notify_rx);
+    }
+    let watched = Watcher::new_raw(notify_tx).and_then(|mut watcher : RecommendedWatcher| {
+        watcher.watch(resources_path, RecursiveMode::Recursive)?;
+        Ok(watcher)
+    });
+    match watched {
+        Ok(watcher) => (Some(watcher), notify_rx),
+        Err(err) => {
+            println!("Sound worker couldn't watch {:?} for changes err -> {:?}", resources_path, err);
+            let _ = report_tx.send(SoundEngineReport::ResourcesUnwatched(format!("{:?}", err)));
+            (None, notify_rx)
+        },
+    }
+}
+
+// after a device couldn't be used, falls back to the default one, then gives that a few more tries (a device that's just come back can take a moment)
+// false when it's time to give up
+fn retry_device(device: &mut Option<String>, failures: &mut u32) -> bool {
+    *failures += 1;
+    if device.is_some() {
+        println!("Sound worker is falling back to the default output device");
+        *device = None;
+        true
+    } else if *failures <= DEVICE_RETRIES {
+        println!("Sound worker is retrying the default output device in {}ms", DEVICE_RETRY_MS);
+        thread::sleep(Duration::from_millis(DEVICE_RETRY_MS));
+        true
+    } else {
+        println!("Sound worker couldn't get an output device after {} tries and is shutting down", failures);
+        false
+    }
+}
+
+enum WorkerAction {
+    Continue,
+    Shutdown,
+    SwitchDevice(Option<String>),
+    DeviceLost(Option<SoundEngineUpdate>), // with the update to replay once rebuilt
+}
+
+// only when the device says so, failing to ask isn't a loss
+fn device_lost<B: SoundBackend>(cb: &SoundContext<B>) -> bool {
+    !cb.backend.connected().unwrap_or(true)
+}
+
+fn tick<B: SoundBackend>(engine: &mut SoundEngine, cb: &mut SoundContext<B>) -> WorkerAction {
+    match engine.tick(cb, time::precise_time_ns()) {
+        Ok(_) => WorkerAction::Continue,
+        Err(err) => {
+            if device_lost(cb) {
+                println!("Sound engine received error {:?} while ticking after losing its output device, rebuilding", err);
+                WorkerAction::DeviceLost(None)
+            } else {
+                println!("Sound engine received unrecoverable error {:?} while ticking and is shutting down", err);
+                WorkerAction::Shutdown
+            }
+        },
+    }
+}
+
+fn process_update<B: SoundBackend>(engine: &mut SoundEngine, cb: &mut SoundContext<B>, notify_rx: &Receiver<RawEvent>, report_tx: &Sender<SoundEngineReport>, event: SoundEngineUpdate) -> WorkerAction {
+    // println!("worker receiving event {:?}", event);
+
+    if let SoundEngineUpdate::Device(device) = event {
+        return WorkerAction::SwitchDevice(device);
+    }
+
+    // a disconnected device doesn't necessarily error, its sources just stop
+    if device_lost(cb) {
+        println!("Sound worker lost its output device, rebuilding");
+        return WorkerAction::DeviceLost(Some(event));
+    }
+
+    let stopping = match event {
+        SoundEngineUpdate::Stop => true,
+        _ => false,
+    };
+
+    let mut purge = false;
+    'fs: loop {
+        match notify_rx.try_recv() {
+            Ok(RawEvent { path, op:_, cookie:_ }) => {
+                println!("sound worker noticed path changed -> {:?}", path);
+                purge = true;
+            }
+            Err(_) => {
+                break 'fs;
+            }
+        }
+    }
+
+    if purge {
+        // at some point we could do smarter purging
+        println!("sound worker noticed file system changes, purging buffers and reloading the manifest");
+        cb.reload_manifest();
+        if engine.process(cb, SoundEngineUpdate::Clear).is_err() {
+            return WorkerAction::Shutdown;
+        }
+    }
+
+    let result = engine.process(cb, event);
+    for report in engine.reports.drain(..) {
+        let _ = report_tx.send(report);
+    }
+
+    match result {
+        Ok(true) => WorkerAction::Continue,
+        Ok(false) => {
+            // println!("Sound engine shutting down");
+            WorkerAction::Shutdown
+        },
+        Err(err) => {
+            if !stopping && device_lost(cb) {
+                println!("Sound engine received error {:?} after losing its output device, rebuilding", err);
+                WorkerAction::DeviceLost(None)
+            } else {
+                println!("Sound engine received unrecoverable error {:?} and is shutting down", err);
+                WorkerAction::Shutdown
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand;
+
+    use backend::ContextAttributes;
+    use super::SoundWorkerConfig;
+
+    fn granted(mono: u32, stereo: u32) -> ContextAttributes {
+        ContextAttributes { mono_sources: Some(mono), stereo_sources: Some(stereo), ..ContextAttributes::default() }
+    }
+
+    #[test]
+    fn source_counts_are_capped_by_the_granted_total() {
+        let config = SoundWorkerConfig::new(String::new(), Vec::new(), rand::XorShiftRng::new_unseeded());
+
+        // OpenAL Soft's defaults, a single stereo slot doesn't limit the streams
+        assert_eq!(config.source_counts(&granted(255, 1)), (32, 4));
+        assert_eq!(config.source_counts(&granted(20, 2)), (18, 4));
+        assert_eq!(config.source_counts(&granted(2, 1)), (0, 3));
+        assert_eq!(config.source_counts(&ContextAttributes::default()), (32, 4));
+    }
+}
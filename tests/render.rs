@@ -0,0 +1,82 @@
+extern crate howl;
+extern crate hound;
+extern crate rand;
+extern crate aphid;
+
+use std::env;
+
+use howl::{SoundEvent, Listener};
+use howl::engine::{SoundEngineUpdate, SoundRender};
+use howl::render::{ScriptedUpdate, render, render_to_wav};
+use howl::worker::SoundWorkerConfig;
+use aphid::HashMap;
+
+const FIXTURES : &'static str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+fn config() -> SoundWorkerConfig {
+    SoundWorkerConfig::new(FIXTURES.to_string(), vec!["wav".to_string()], rand::XorShiftRng::new_unseeded())
+}
+
+fn play(name: &str, gain: f32) -> SoundEngineUpdate {
+    SoundEngineUpdate::Render(SoundRender {
+        master_gain: 1.0,
+        sounds: vec![SoundEvent {
+            name: name.to_string(),
+            position: [0.0, 0.0, 0.0],
+            gain: gain,
+            pitch: 1.0,
+            attenuation: 1.0,
+            loop_sound: false,
+            reverb_send: 0.0,
+            lowpass: None,
+            bus: None,
+        }],
+        persistent_sounds: HashMap::default(),
+        listener: Listener::default(),
+        reverb: None,
+        buses: HashMap::default(),
+    })
+}
+
+// tone.wav (stereo, 44.1kHz, 0.05s) at half gain, then again over it at a quarter from 1102 frames in
+fn script() -> Vec<ScriptedUpdate> {
+    vec![(0.0, play("tone", 0.5)), (0.025, play("tone", 0.25))]
+}
+
+fn samples(path: &str) -> (hound::WavSpec, Vec<i16>) {
+    let mut reader = hound::WavReader::open(path).unwrap();
+    let samples = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+    (reader.spec(), samples)
+}
+
+#[test]
+fn render_is_deterministic() {
+    let first = render(config(), script(), 0.1).unwrap();
+    let second = render(config(), script(), 0.1).unwrap();
+    assert_eq!(first.len(), 4410 * 2);
+    assert!(first == second);
+}
+
+#[test]
+fn render_rejects_script_times_that_arent_finite() {
+    use std::f64;
+    assert!(render(config(), vec![(f64::NAN, play("tone", 0.5))], 0.1).is_err());
+    assert!(render(config(), vec![(0.0, play("tone", 0.5)), (f64::INFINITY, play("tone", 0.5))], 0.1).is_err());
+    assert!(render(config(), vec![(-1.0, play("tone", 0.5))], 0.1).is_err());
+}
+
+// golden_render.wav is the expected output of the script, after a deliberate change to the mix regenerate it with render_to_wav
+#[test]
+fn render_matches_the_golden_file() {
+    let rendered = env::temp_dir().join("howl_golden_render.wav");
+    render_to_wav(&rendered, config(), script(), 0.1).unwrap();
+
+    let (expected_spec, expected) = samples(&format!("{}/golden_render.wav", FIXTURES));
+    let (spec, actual) = samples(rendered.to_str().unwrap());
+
+    assert_eq!((spec.channels, spec.sample_rate, spec.bits_per_sample), (expected_spec.channels, expected_spec.sample_rate, expected_spec.bits_per_sample));
+    assert_eq!(actual.len(), expected.len());
+    if let Some(at) = actual.iter().zip(expected.iter()).position(|(a, e)| a != e) {
+        panic!("render differs from the golden file at sample {}: {} instead of {}", at, actual[at], expected[at]);
+    }
+}